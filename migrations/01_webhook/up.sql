@@ -0,0 +1,21 @@
+CREATE TABLE "webhook"
+(
+    "id"        bytea PRIMARY KEY,
+    "timestamp" timestamptz NOT NULL,
+    "url"       text        NOT NULL,
+    "secret"    text        NOT NULL,
+    "events"    text[]      NOT NULL
+);
+
+CREATE TABLE "webhook_delivery"
+(
+    "id"          bytea PRIMARY KEY,
+    "webhook_id"  bytea       NOT NULL REFERENCES "webhook" ("id") ON DELETE CASCADE,
+    "timestamp"   timestamptz NOT NULL,
+    "event"       text        NOT NULL,
+    "attempt"     integer     NOT NULL,
+    "status_code" integer,
+    "error"       text
+);
+
+CREATE INDEX ON "webhook_delivery" ("webhook_id", "id");
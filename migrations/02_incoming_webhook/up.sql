@@ -0,0 +1,8 @@
+CREATE TABLE "incoming_webhook"
+(
+    "id"         bytea PRIMARY KEY,
+    "timestamp"  timestamptz NOT NULL,
+    "name"       text        NOT NULL,
+    -- The SHA-256 of the token, so that a copy of the database cannot be used to post messages
+    "token_hash" text        NOT NULL UNIQUE
+);
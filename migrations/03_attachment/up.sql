@@ -0,0 +1,12 @@
+CREATE TABLE "attachment"
+(
+    "id"           bytea PRIMARY KEY,
+    "message_id"   bytea REFERENCES "message" ("id") ON DELETE CASCADE,
+    "timestamp"    timestamptz NOT NULL,
+    "filename"     text,
+    "content_type" text        NOT NULL,
+    "size"         bigint      NOT NULL,
+    "checksum"     text        NOT NULL
+);
+
+CREATE INDEX ON "attachment" ("message_id");
@@ -0,0 +1 @@
+ALTER TABLE "message" ADD COLUMN "body_html" text;
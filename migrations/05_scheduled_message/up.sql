@@ -0,0 +1,13 @@
+CREATE TABLE "scheduled_message"
+(
+    "id"                   bytea PRIMARY KEY,
+    "timestamp"            timestamptz NOT NULL,
+    "send_at"              timestamptz NOT NULL,
+    "body"                 text        NOT NULL,
+    -- Set once the message is sent, until its publish is confirmed
+    "message_id"           bytea REFERENCES "message" ("id") ON DELETE CASCADE,
+    "publish_attempted_at" timestamptz
+);
+
+CREATE INDEX ON "scheduled_message" ("send_at");
+CREATE INDEX ON "scheduled_message" ("publish_attempted_at") WHERE "message_id" IS NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE "message" ADD COLUMN "expires_at" timestamptz;
+
+CREATE INDEX ON "message" ("expires_at") WHERE "expires_at" IS NOT NULL;
@@ -0,0 +1,9 @@
+CREATE TABLE "message_archive"
+(
+    "id"               bytea PRIMARY KEY,
+    "timestamp"        timestamptz NOT NULL,
+    "path"             text        NOT NULL,
+    "message_count"    bigint      NOT NULL,
+    "first_message_id" bytea       NOT NULL,
+    "last_message_id"  bytea       NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE "message" ADD COLUMN "client_message_id" text UNIQUE;
@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS "message_change_compaction";
+DROP TABLE IF EXISTS "message_change";
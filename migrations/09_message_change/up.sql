@@ -0,0 +1,29 @@
+CREATE TABLE "message_change"
+(
+    "seq"        bigserial PRIMARY KEY,
+    -- The ID of the transaction that made the change, so that clients only sync past changes
+    -- whose transaction has ended, without serializing writers
+    "txid"       bigint      NOT NULL DEFAULT pg_current_xact_id()::text::bigint,
+    "message_id" bytea       NOT NULL,
+    "kind"       text        NOT NULL,
+    "timestamp"  timestamptz NOT NULL
+);
+
+CREATE INDEX ON "message_change" ("txid", "seq");
+CREATE INDEX ON "message_change" ("timestamp");
+
+-- Existing messages were committed before any client could sync
+INSERT INTO "message_change" ("txid", "message_id", "kind", "timestamp")
+SELECT 0, "id", 'created', "timestamp"
+FROM "message"
+ORDER BY "id";
+
+CREATE TABLE "message_change_compaction"
+(
+    "id"           boolean PRIMARY KEY DEFAULT true CHECK ("id"),
+    "through_txid" bigint NOT NULL,
+    "through"      bigint NOT NULL
+);
+
+INSERT INTO "message_change_compaction" ("through_txid", "through")
+VALUES (0, 0);
@@ -0,0 +1,9 @@
+CREATE TABLE "flagged_message"
+(
+    "id"         bytea PRIMARY KEY,
+    "message_id" bytea       NOT NULL REFERENCES "message" ("id") ON DELETE CASCADE,
+    "timestamp"  timestamptz NOT NULL,
+    "reasons"    text[]      NOT NULL
+);
+
+CREATE INDEX ON "flagged_message" ("message_id");
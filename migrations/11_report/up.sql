@@ -0,0 +1,12 @@
+CREATE TABLE "report"
+(
+    "id"          bytea PRIMARY KEY,
+    "message_id"  bytea       NOT NULL,
+    "timestamp"   timestamptz NOT NULL,
+    "reason"      text        NOT NULL,
+    "status"      text        NOT NULL,
+    "resolved_at" timestamptz
+);
+
+CREATE INDEX ON "report" ("status", "id");
+CREATE INDEX ON "report" ("message_id");
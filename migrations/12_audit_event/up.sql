@@ -0,0 +1,36 @@
+CREATE TABLE "audit_event"
+(
+    "seq"         bigserial PRIMARY KEY,
+    "timestamp"   timestamptz NOT NULL,
+    "actor"       text        NOT NULL,
+    "action"      text        NOT NULL,
+    "target_type" text        NOT NULL,
+    "target_id"   text        NOT NULL,
+    "before"      jsonb,
+    "after"       jsonb,
+    "request_id"  text
+);
+
+CREATE INDEX ON "audit_event" ("timestamp");
+CREATE INDEX ON "audit_event" ("actor", "seq");
+CREATE INDEX ON "audit_event" ("action", "seq");
+CREATE INDEX ON "audit_event" ("target_type", "target_id", "seq");
+
+CREATE FUNCTION "audit_event_append_only"() RETURNS trigger AS
+$$
+BEGIN
+    RAISE EXCEPTION 'audit_event is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "audit_event_append_only"
+    BEFORE UPDATE OR DELETE
+    ON "audit_event"
+    FOR EACH ROW
+EXECUTE FUNCTION "audit_event_append_only"();
+
+CREATE TRIGGER "audit_event_no_truncate"
+    BEFORE TRUNCATE
+    ON "audit_event"
+    FOR EACH STATEMENT
+EXECUTE FUNCTION "audit_event_append_only"();
@@ -0,0 +1,137 @@
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::DatabaseError;
+use crate::MIGRATIONS;
+use deadpool_diesel::postgres::Pool;
+use diesel::dsl::sql;
+use diesel::migration::MigrationSource;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use diesel::PgConnection;
+use diesel_migrations::MigrationHarness;
+use serde_json::json;
+
+/// An embedded migration and whether it has been run
+pub struct MigrationStatus {
+    pub name: String,
+    pub applied: bool,
+}
+
+fn migration_error(e: impl ToString) -> DatabaseError {
+    DatabaseError {
+        message: e.to_string(),
+    }
+}
+
+/// Record that a migration was run or reverted, if the audit log exists at this version of the
+/// schema
+fn record_migration(
+    client: &mut PgConnection,
+    actor: &Actor,
+    action: &str,
+    version: &str,
+) -> QueryResult<()> {
+    let logged = diesel::select(sql::<Bool>("to_regclass('audit_event') IS NOT NULL"))
+        .get_result::<bool>(client)?;
+    if !logged {
+        return Ok(());
+    }
+    AuditEvent::record(
+        client,
+        vec![AuditEvent::new(
+            actor,
+            action,
+            "migration",
+            version.as_bytes(),
+            None,
+            Some(json!({ "version": version })),
+        )],
+    )
+}
+
+/// Every embedded migration, oldest first
+pub async fn migrations(pool: &Pool) -> Result<Vec<MigrationStatus>, DatabaseError> {
+    let client = pool.get().await?;
+    let results = client
+        .interact(|client| {
+            let applied = client.applied_migrations().map_err(migration_error)?;
+            let mut migrations =
+                MigrationSource::<Pg>::migrations(&MIGRATIONS).map_err(migration_error)?;
+            // Versions sort in the order migrations are run, unlike names
+            migrations.sort_by_key(|migration| migration.name().version().as_owned());
+            Ok::<_, DatabaseError>(
+                migrations
+                    .iter()
+                    .map(|migration| MigrationStatus {
+                        name: migration.name().to_string(),
+                        applied: applied.contains(&migration.name().version().as_owned()),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await??;
+
+    Ok(results)
+}
+
+/// Run every pending migration in one transaction, recording each in the audit log, and return
+/// the versions that were run
+pub async fn run_migrations(pool: &Pool, actor: &Actor) -> Result<Vec<String>, DatabaseError> {
+    let actor = actor.clone();
+    let client = pool.get().await?;
+    let results = client
+        .interact(move |client| {
+            client.transaction(|client| {
+                let versions: Vec<String> = client
+                    .run_pending_migrations(MIGRATIONS)
+                    .map(|versions| versions.iter().map(ToString::to_string).collect())
+                    .map_err(migration_error)?;
+                for version in &versions {
+                    record_migration(client, &actor, "migration.run", version)?;
+                }
+                Ok::<_, DatabaseError>(versions)
+            })
+        })
+        .await??;
+
+    Ok(results)
+}
+
+/// Revert the most recently run migration, which must be `version`, so that its tables are
+/// never dropped by mistake. The revert is recorded in the audit log, though reverting the
+/// migration that created the log drops the record along with it.
+pub async fn revert_migration(
+    pool: &Pool,
+    actor: &Actor,
+    version: String,
+) -> Result<String, DatabaseError> {
+    let actor = actor.clone();
+    let client = pool.get().await?;
+    let result = client
+        .interact(move |client| {
+            client.transaction(|client| {
+                let last = client
+                    .applied_migrations()
+                    .map_err(migration_error)?
+                    .first()
+                    .map(ToString::to_string);
+                if last.as_deref() != Some(version.as_str()) {
+                    return Err(DatabaseError {
+                        message: format!(
+                            "The most recently run migration is {}, not {}",
+                            last.as_deref().unwrap_or("none"),
+                            version
+                        ),
+                    });
+                }
+                record_migration(client, &actor, "migration.revert", &version)?;
+                client
+                    .revert_last_migration(MIGRATIONS)
+                    .map_err(migration_error)?;
+                Ok(version)
+            })
+        })
+        .await??;
+
+    Ok(result)
+}
@@ -3,14 +3,20 @@ use base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use base64::Engine;
 use deadpool::managed::{Manager, Object, Pool, RecycleError};
 use juniper::futures::{Stream, StreamExt};
-use lapin::options::{BasicPublishOptions, ExchangeDeclareOptions, QueueDeclareOptions};
+use lapin::acker::Acker;
+use lapin::message::Delivery;
+use lapin::options::{
+    BasicNackOptions, BasicPublishOptions, BasicQosOptions, BasicRejectOptions,
+    ConfirmSelectOptions, ExchangeDeclareOptions, QueueDeclareOptions,
+};
 use lapin::{Channel, Connection, ConnectionProperties};
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
+use std::sync::Arc;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumIter, IntoStaticStr};
 use time::OffsetDateTime;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
 #[derive(Debug)]
 pub struct AmqpError {
@@ -39,18 +45,28 @@ pub trait Protobuf: Sized {
 struct ChannelManager {
     url: String,
     connection: Mutex<Connection>,
+    confirm: bool,
 }
 
 impl ChannelManager {
-    pub async fn new(url: String) -> Self {
+    pub async fn new(url: String, confirm: bool) -> Self {
         let connection = Connection::connect(url.as_str(), ConnectionProperties::default())
             .await
             .unwrap();
         Self {
             url,
             connection: Mutex::new(connection),
+            confirm,
         }
     }
+
+    pub async fn close(&self) -> Result<(), AmqpError> {
+        let conn = self.connection.lock().await;
+        if conn.status().connected() {
+            conn.close(200, "Shutting down").await?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -67,7 +83,13 @@ impl Manager for ChannelManager {
             *conn = connection;
         }
 
-        Ok(conn.create_channel().await?)
+        let channel = conn.create_channel().await?;
+        if self.confirm {
+            channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await?;
+        }
+        Ok(channel)
     }
 
     async fn recycle(
@@ -84,17 +106,64 @@ impl Manager for ChannelManager {
 #[derive(IntoStaticStr, EnumIter)]
 pub enum Exchange {
     Messages,
+    Moderation,
+}
+
+/// Decrements the in-flight publish count when dropped
+struct InFlight(Arc<watch::Sender<usize>>);
+
+impl InFlight {
+    fn new(count: Arc<watch::Sender<usize>>) -> Self {
+        count.send_modify(|count| *count += 1);
+        Self(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+/// A message from a shared queue. The broker redelivers it, to this or another instance of the
+/// server, until it is acknowledged.
+pub struct Pending<T> {
+    pub payload: T,
+    acker: Acker,
+}
+
+impl<T> Pending<T> {
+    /// Acknowledge that the message has been handled
+    pub async fn ack(self) {
+        self.acker
+            .ack(Default::default())
+            .await
+            .unwrap_or_else(|err| println!("Error acknowledging message: {}", err));
+    }
+
+    /// Return the message to the queue, to be handled again
+    pub async fn requeue(self) {
+        self.acker
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap_or_else(|err| println!("Error requeueing message: {}", err));
+    }
 }
 
 #[derive(Clone)]
 pub struct AmqpClient {
     producer: Pool<ChannelManager>,
     consumer: Pool<ChannelManager>,
+    in_flight: Arc<watch::Sender<usize>>,
+    closing: Arc<watch::Sender<bool>>,
 }
 
 impl AmqpClient {
     pub async fn new(url: String) -> Self {
-        let producer = Pool::builder(ChannelManager::new(url.clone()).await)
+        let producer = Pool::builder(ChannelManager::new(url.clone(), true).await)
             .build()
             .unwrap();
 
@@ -115,11 +184,35 @@ impl AmqpClient {
                 .unwrap();
         }
 
-        let consumer = Pool::builder(ChannelManager::new(url.clone()).await)
+        let consumer = Pool::builder(ChannelManager::new(url.clone(), false).await)
             .build()
             .unwrap();
 
-        Self { producer, consumer }
+        Self {
+            producer,
+            consumer,
+            in_flight: Arc::new(watch::channel(0).0),
+            closing: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Publish in the background, logging failures. Publishes started this way are still
+    /// awaited by [`AmqpClient::close`].
+    pub fn produce_in_background(
+        &self,
+        payload: impl Protobuf + Send + 'static,
+        exchange: Exchange,
+        routing_key: &'static str,
+    ) {
+        let client = self.clone();
+        // Counted before spawning, so that a publish is never missed by `drain`
+        let in_flight = InFlight::new(self.in_flight.clone());
+        actix_rt::spawn(async move {
+            if let Err(err) = client.publish(payload, exchange, routing_key).await {
+                println!("Error sending message: {}", err);
+            }
+            drop(in_flight);
+        });
     }
 
     pub async fn produce(
@@ -127,12 +220,24 @@ impl AmqpClient {
         payload: impl Protobuf,
         exchange: Exchange,
         routing_key: &str,
+    ) -> Result<(), AmqpError> {
+        let _in_flight = InFlight::new(self.in_flight.clone());
+        self.publish(payload, exchange, routing_key).await
+    }
+
+    /// Publish and wait for the broker's confirmation, without counting the publish as in
+    /// flight
+    async fn publish(
+        &self,
+        payload: impl Protobuf,
+        exchange: Exchange,
+        routing_key: &str,
     ) -> Result<(), AmqpError> {
         let object = self.producer.get().await.map_err(|e| AmqpError {
             message: e.to_string(),
         })?;
         let channel: &Channel = object.as_ref();
-        channel
+        let confirmation = channel
             .basic_publish(
                 exchange.into(),
                 routing_key,
@@ -140,64 +245,184 @@ impl AmqpClient {
                 &payload.try_to_protobuf()?,
                 Default::default(),
             )
+            .await?
             .await?;
+        if confirmation.is_nack() {
+            return Err(AmqpError {
+                message: "Publish was not acknowledged by the broker".to_string(),
+            });
+        }
         Ok(())
     }
 
+    /// Consume from a new exclusive queue, so that every consumer receives every message.
+    /// Messages are acknowledged as they are received.
     pub async fn consume<T: Protobuf>(
         &self,
         exchange: Exchange,
         routing_key: &str,
     ) -> Result<impl Stream<Item = T>, AmqpError> {
-        let object = self.consumer.get().await.map_err(|e| AmqpError {
-            message: e.to_string(),
-        })?;
-        let channel: &Channel = object.as_ref();
         let queue_id = BASE64_URL_SAFE_NO_PAD.encode(snowflake(OffsetDateTime::now_utc()));
-
-        channel
-            .queue_declare(
+        let deliveries = self
+            .consume_from(
                 queue_id.as_str(),
                 QueueDeclareOptions {
                     exclusive: true,
                     auto_delete: true,
                     ..Default::default()
                 },
-                Default::default(),
+                exchange,
+                routing_key,
+                0,
+            )
+            .await?;
+
+        Ok(deliveries.filter_map(|delivery| async move {
+            let payload = T::try_from_protobuf(&delivery.data)
+                .map_err(|err| println!("Error decoding message: {}", err))
+                .ok()?;
+            delivery
+                .ack(Default::default())
+                .await
+                .unwrap_or_else(|err| println!("Error acknowledging message: {}", err));
+            Some(payload)
+        }))
+    }
+
+    /// Consume from a durable queue shared by every instance of the server, so that each
+    /// message is received by only one consumer. Messages must be acknowledged with
+    /// [`Pending::ack`] once they are handled, and are redelivered if the server stops first.
+    /// Messages that cannot be decoded are rejected. At most `prefetch` messages are delivered
+    /// to this consumer before it acknowledges them, so that one instance cannot take the whole
+    /// queue.
+    pub async fn consume_shared<T: Protobuf>(
+        &self,
+        queue: &str,
+        exchange: Exchange,
+        routing_key: &str,
+        prefetch: u16,
+    ) -> Result<impl Stream<Item = Pending<T>>, AmqpError> {
+        let deliveries = self
+            .consume_from(
+                queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                exchange,
+                routing_key,
+                prefetch,
             )
             .await?;
+
+        Ok(deliveries.filter_map(|delivery| async move {
+            match T::try_from_protobuf(&delivery.data) {
+                Ok(payload) => Some(Pending {
+                    payload,
+                    acker: delivery.acker,
+                }),
+                Err(err) => {
+                    println!("Error decoding message: {}", err);
+                    delivery
+                        .reject(BasicRejectOptions { requeue: false })
+                        .await
+                        .unwrap_or_else(|err| println!("Error rejecting message: {}", err));
+                    None
+                }
+            }
+        }))
+    }
+
+    /// Consume from the queue, with at most `prefetch` unacknowledged messages, or no limit if
+    /// it is zero. Channels are shared, so the limit is set for every consumer.
+    async fn consume_from(
+        &self,
+        queue_id: &str,
+        options: QueueDeclareOptions,
+        exchange: Exchange,
+        routing_key: &str,
+        prefetch: u16,
+    ) -> Result<impl Stream<Item = Delivery>, AmqpError> {
+        let object = self.consumer.get().await.map_err(|e| AmqpError {
+            message: e.to_string(),
+        })?;
+        let channel: &Channel = object.as_ref();
+
+        channel
+            .queue_declare(queue_id, options, Default::default())
+            .await?;
         channel
             .queue_bind(
-                queue_id.as_str(),
+                queue_id,
                 exchange.into(),
                 routing_key,
                 Default::default(),
                 Default::default(),
             )
             .await?;
+        channel
+            .basic_qos(prefetch, BasicQosOptions::default())
+            .await?;
+        let consumer_tag = BASE64_URL_SAFE_NO_PAD.encode(snowflake(OffsetDateTime::now_utc()));
         let consumer = channel
             .basic_consume(
-                queue_id.as_str(),
-                queue_id.as_str(),
+                queue_id,
+                &consumer_tag,
                 Default::default(),
                 Default::default(),
             )
             .await?;
 
-        let result = consumer.filter_map(|message| async {
-            let message = message
-                .map_err(|err| println!("Error consuming message: {}", err))
-                .ok()?;
-            let payload = T::try_from_protobuf(&message.data)
-                .map_err(|err| println!("Error decoding message: {}", err))
-                .ok()?;
-            message
-                .ack(Default::default())
-                .await
-                .unwrap_or_else(|err| println!("Error acknowledging message: {}", err));
-            Some(payload)
-        });
+        let mut closing = self.closing.subscribe();
+        let closed = async move {
+            while !*closing.borrow() {
+                if closing.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+
+        let result = consumer
+            .take_until(closed)
+            .filter_map(|delivery| async move {
+                delivery
+                    .map_err(|err| println!("Error consuming message: {}", err))
+                    .ok()
+            });
 
         Ok(result)
     }
+
+    /// End every consumer stream, so that open subscriptions complete
+    pub fn stop_consuming(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Wait until every pending publish is confirmed. Callers bound this with a timeout.
+    pub async fn drain(&self) {
+        let mut in_flight = self.in_flight.subscribe();
+        while *in_flight.borrow() > 0 {
+            if in_flight.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// The number of publishes waiting to be confirmed
+    pub fn pending(&self) -> usize {
+        *self.in_flight.borrow()
+    }
+
+    /// Stop consuming and close both connections. Publishes that are still pending fail, so
+    /// call [`AmqpClient::drain`] first.
+    pub async fn close(&self) {
+        self.stop_consuming();
+        self.producer.close();
+        self.consumer.close();
+        for manager in [self.producer.manager(), self.consumer.manager()] {
+            if let Err(err) = manager.close().await {
+                println!("Error closing AMQP connection: {}", err);
+            }
+        }
+    }
 }
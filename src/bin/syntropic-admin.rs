@@ -0,0 +1,381 @@
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use clap::{Args, Parser, Subcommand};
+use juniper::futures::StreamExt;
+use std::env::var;
+use std::io::{stdout, Write};
+use std::process::exit;
+use std::time::Duration;
+use syntropic_api::admin::{migrations, revert_migration, run_migrations};
+use syntropic_api::amqp::AmqpClient;
+use syntropic_api::models::audit_event::{new_request_id, Actor, AuditEvent, AuditEventFilter};
+use syntropic_api::models::flagged_message::FlaggedMessage;
+use syntropic_api::models::incoming_webhook::IncomingWebhook;
+use syntropic_api::models::message::Message;
+use syntropic_api::models::report::{Report, ReportStatus};
+use syntropic_api::snowflake::decode;
+use syntropic_api::{amqp_client, blob_store, pool};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::time::timeout;
+
+/// How long to wait for background publishes before exiting
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Operate a Syntropic deployment. Connects with the same environment variables as the server.
+#[derive(Parser)]
+#[command(name = "syntropic-admin")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the database schema
+    #[command(subcommand)]
+    Migrations(MigrationsCommand),
+    /// Manage incoming webhooks, whose URLs are tokens for sending messages
+    #[command(subcommand)]
+    IncomingWebhook(IncomingWebhookCommand),
+    /// Read the audit log of privileged actions. Events name the client's address and may hold
+    /// the objects acted on, so they are only readable here.
+    #[command(subcommand)]
+    AuditEvents(AuditEventsCommand),
+    /// Review messages that moderation filters flagged
+    #[command(subcommand)]
+    Flagged(FlaggedCommand),
+    /// Review users' reports of messages
+    #[command(subcommand)]
+    Reports(ReportsCommand),
+    /// Publish the messages sent in a time range again, for consumers that missed them
+    Republish {
+        /// The start of the range, as an RFC 3339 time
+        #[arg(long, value_parser = parse_time)]
+        since: OffsetDateTime,
+        /// The end of the range, as an RFC 3339 time. Defaults to now.
+        #[arg(long, value_parser = parse_time)]
+        until: Option<OffsetDateTime>,
+    },
+    /// Decode a snowflake ID, given as the API shows it or as an integer
+    Snowflake { id: String },
+}
+
+#[derive(Subcommand)]
+enum MigrationsCommand {
+    /// List every migration and whether it has been run
+    List,
+    /// Run every pending migration
+    Run,
+    /// Revert the most recently run migration. Reverting can drop tables and their data, such
+    /// as the audit log, so the migration's version must be given to confirm.
+    Revert {
+        /// The version of the most recently run migration, the number its name starts with
+        #[arg(long)]
+        version: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum IncomingWebhookCommand {
+    /// Create an incoming webhook and print its URL
+    Create { name: String },
+}
+
+#[derive(Subcommand)]
+enum FlaggedCommand {
+    /// List the review queue, oldest first
+    List,
+    /// Remove a message from the review queue, keeping the message
+    Dismiss { id: String },
+    /// Delete a flagged message, removing it from the review queue
+    Remove { id: String },
+}
+
+#[derive(Subcommand)]
+enum ReportsCommand {
+    /// List reports, oldest first
+    List {
+        /// Only reports with this status: open, dismissed or deleted
+        #[arg(long, value_parser = parse_report_status)]
+        status: Option<ReportStatus>,
+        /// Only reports made after the report with this ID
+        #[arg(long)]
+        after: Option<String>,
+        /// The most reports to list
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Close an open report, keeping the message
+    Dismiss { id: String },
+    /// Delete a reported message, closing every open report of it
+    DeleteMessage { id: String },
+}
+
+#[derive(Subcommand)]
+enum AuditEventsCommand {
+    /// Print the most recent matching events, newest first, as newline-delimited JSON
+    List {
+        #[command(flatten)]
+        filter: AuditFilterArgs,
+        /// Only events recorded before the event with this ID
+        #[arg(long)]
+        after: Option<i64>,
+        /// The most events to print
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Print every matching event, oldest first, as newline-delimited JSON
+    Export {
+        #[command(flatten)]
+        filter: AuditFilterArgs,
+    },
+}
+
+/// Which audit events to print. Every given option must match.
+#[derive(Args)]
+struct AuditFilterArgs {
+    /// Only events by this actor
+    #[arg(long)]
+    actor: Option<String>,
+    /// Only events with this action, such as webhook.delete
+    #[arg(long)]
+    action: Option<String>,
+    /// Only events on this kind of object, such as webhook
+    #[arg(long)]
+    target_type: Option<String>,
+    /// Only events on the object with this ID
+    #[arg(long)]
+    target_id: Option<String>,
+    /// Only events at or after this RFC 3339 time
+    #[arg(long, value_parser = parse_time)]
+    since: Option<OffsetDateTime>,
+    /// Only events before this RFC 3339 time
+    #[arg(long, value_parser = parse_time)]
+    until: Option<OffsetDateTime>,
+}
+
+impl From<AuditFilterArgs> for AuditEventFilter {
+    fn from(args: AuditFilterArgs) -> Self {
+        AuditEventFilter {
+            actor: args.actor,
+            action: args.action,
+            target_type: args.target_type,
+            target_id: args.target_id,
+            since: args.since,
+            until: args.until,
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(time, &Rfc3339).map_err(|e| e.to_string())
+}
+
+fn parse_report_status(status: &str) -> Result<ReportStatus, String> {
+    ReportStatus::parse(status).ok_or_else(|| format!("Unknown report status: {}", status))
+}
+
+fn decode_id(id: &str) -> Result<Vec<u8>, String> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(id)
+        .map_err(|e| format!("Invalid ID: {}", e))
+}
+
+/// An ID is read as base64 if it decodes to eight bytes. Snowflakes as integers are too long
+/// for that, so anything else is read as an integer.
+fn parse_snowflake(id: &str) -> Option<Vec<u8>> {
+    match BASE64_URL_SAFE_NO_PAD.decode(id) {
+        Ok(bytes) if bytes.len() == 8 => Some(bytes),
+        _ => id.parse::<u64>().ok().map(|id| id.to_be_bytes().to_vec()),
+    }
+}
+
+/// Wait for publishes made in the background, which are lost if the command exits first
+async fn drain(amqp_client: &AmqpClient) {
+    if timeout(DRAIN_TIMEOUT, amqp_client.drain()).await.is_err() {
+        eprintln!("Timed out publishing to the message broker");
+    }
+}
+
+fn print_report(report: &Report) {
+    println!(
+        "{}  {:<9}  message {}  {}",
+        report.id(),
+        report.status().as_str(),
+        report.message_id(),
+        report.reason()
+    );
+}
+
+/// Print an audit event as a line of JSON
+fn print_event(event: &AuditEvent) -> Result<(), String> {
+    let mut out = stdout().lock();
+    serde_json::to_writer(&mut out, event).map_err(|e| e.to_string())?;
+    writeln!(out).map_err(|e| e.to_string())
+}
+
+/// The person running the command, for the audit log
+fn actor() -> Actor {
+    let user = var("USER").unwrap_or("unknown".to_string());
+    Actor::new(format!("cli:{}", user), Some(new_request_id()))
+}
+
+async fn run(command: Command) -> Result<(), String> {
+    match command {
+        Command::Migrations(MigrationsCommand::List) => {
+            for migration in migrations(&pool()).await.map_err(|e| e.message)? {
+                let status = if migration.applied {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{:<8} {}", status, migration.name);
+            }
+        }
+        Command::Migrations(MigrationsCommand::Run) => {
+            let versions = run_migrations(&pool(), &actor())
+                .await
+                .map_err(|e| e.message)?;
+            if versions.is_empty() {
+                println!("No pending migrations");
+            }
+            for version in versions {
+                println!("Ran {}", version);
+            }
+        }
+        Command::Migrations(MigrationsCommand::Revert { version }) => {
+            let version = revert_migration(&pool(), &actor(), version)
+                .await
+                .map_err(|e| e.message)?;
+            println!("Reverted {}", version);
+        }
+        Command::IncomingWebhook(IncomingWebhookCommand::Create { name }) => {
+            let webhook = IncomingWebhook::create(&pool(), &actor(), name)
+                .await
+                .map_err(|e| e.message)?;
+            println!("id:  {}", webhook.id());
+            println!("url: {}", webhook.url());
+        }
+        Command::AuditEvents(AuditEventsCommand::List {
+            filter,
+            after,
+            limit,
+        }) => {
+            let events = AuditEvent::list(&pool(), filter.into(), after, limit)
+                .await
+                .map_err(|e| e.message)?;
+            for event in &events {
+                print_event(event)?;
+            }
+        }
+        Command::AuditEvents(AuditEventsCommand::Export { filter }) => {
+            let mut events = Box::pin(AuditEvent::export(pool(), filter.into()));
+            while let Some(event) = events.next().await {
+                print_event(&event.map_err(|e| e.message)?)?;
+            }
+        }
+        Command::Flagged(FlaggedCommand::List) => {
+            for flagged in FlaggedMessage::list(&pool()).await.map_err(|e| e.message)? {
+                println!(
+                    "{}  message {}  {}",
+                    flagged.id(),
+                    flagged.message_id(),
+                    flagged.reasons().join(", ")
+                );
+            }
+        }
+        Command::Flagged(FlaggedCommand::Dismiss { id }) => {
+            if !FlaggedMessage::dismiss(&pool(), &actor(), decode_id(&id)?)
+                .await
+                .map_err(|e| e.message)?
+            {
+                return Err(format!("{} is not in the review queue", id));
+            }
+            println!("Dismissed {}", id);
+        }
+        Command::Flagged(FlaggedCommand::Remove { id }) => {
+            let amqp_client = amqp_client().await;
+            let removed = FlaggedMessage::remove(
+                &pool(),
+                &amqp_client,
+                blob_store().await.as_ref(),
+                &actor(),
+                decode_id(&id)?,
+            )
+            .await
+            .map_err(|e| e.message)?;
+            drain(&amqp_client).await;
+            if !removed {
+                return Err(format!("{} is not in the review queue", id));
+            }
+            println!("Removed the message flagged as {}", id);
+        }
+        Command::Reports(ReportsCommand::List {
+            status,
+            after,
+            limit,
+        }) => {
+            let after = after.as_deref().map(decode_id).transpose()?;
+            let page = Report::list(&pool(), status, after, limit)
+                .await
+                .map_err(|e| e.message)?;
+            for report in page.nodes() {
+                print_report(&report);
+            }
+            if let (true, Some(cursor)) = (page.has_next_page(), page.end_cursor()) {
+                println!("More reports follow; list them with --after {}", cursor);
+            }
+        }
+        Command::Reports(ReportsCommand::Dismiss { id }) => {
+            let amqp_client = amqp_client().await;
+            let report = Report::dismiss(&pool(), &amqp_client, &actor(), decode_id(&id)?)
+                .await
+                .map_err(|e| e.message)?;
+            drain(&amqp_client).await;
+            print_report(&report.ok_or_else(|| format!("Report {} is not open", id))?);
+        }
+        Command::Reports(ReportsCommand::DeleteMessage { id }) => {
+            let amqp_client = amqp_client().await;
+            let report = Report::delete_message(
+                &pool(),
+                &amqp_client,
+                blob_store().await.as_ref(),
+                &actor(),
+                decode_id(&id)?,
+            )
+            .await
+            .map_err(|e| e.message)?;
+            drain(&amqp_client).await;
+            print_report(&report.ok_or_else(|| format!("Report {} is not open", id))?);
+        }
+        Command::Republish { since, until } => {
+            let until = until.unwrap_or_else(OffsetDateTime::now_utc);
+            let published =
+                Message::republish(&pool(), &amqp_client().await, &actor(), since, until)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            println!("Republished {} messages", published);
+        }
+        Command::Snowflake { id } => {
+            let bytes = parse_snowflake(&id).ok_or_else(|| format!("Invalid ID: {}", id))?;
+            let (timestamp, random) = decode(&bytes).ok_or("Not a snowflake ID")?;
+            println!("id:        {}", BASE64_URL_SAFE_NO_PAD.encode(&bytes));
+            println!(
+                "integer:   {}",
+                u64::from_be_bytes(bytes.try_into().unwrap())
+            );
+            println!("timestamp: {}", timestamp.format(&Rfc3339).unwrap());
+            println!("random:    {}", random);
+        }
+    }
+    Ok(())
+}
+
+#[actix_web::main]
+async fn main() {
+    if let Err(e) = run(Cli::parse().command).await {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
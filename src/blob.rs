@@ -0,0 +1,318 @@
+use hmac::{Hmac, Mac};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+#[derive(Debug)]
+pub struct BlobError {
+    pub message: String,
+}
+
+impl Display for BlobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl<T: Error> From<T> for BlobError {
+    fn from(e: T) -> Self {
+        Self {
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Storage for attachment contents, addressed by key
+#[async_trait::async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> Result<(), BlobError>;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError>;
+    async fn delete(&self, key: &str) -> Result<(), BlobError>;
+}
+
+/// Stores each blob as a file in a directory on local disk
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, _content_type: &str, data: Vec<u8>) -> Result<(), BlobError> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        tokio::fs::write(self.root.join(key), data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        match tokio::fs::read(self.root.join(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Stores blobs in a bucket of an S3-compatible service, such as MinIO, using path-style
+/// requests signed with AWS Signature Version 4
+pub struct S3BlobStore {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    http: Client,
+}
+
+impl S3BlobStore {
+    /// Connect to the service, creating the bucket if it does not exist
+    pub async fn connect(
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Result<Self, BlobError> {
+        let store = Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            http: Client::new(),
+        };
+
+        let response = store.request(Method::PUT, "", None, Vec::new()).await?;
+        match response.status() {
+            status if status.is_success() || status == StatusCode::CONFLICT => Ok(store),
+            status => Err(BlobError {
+                message: format!("Creating bucket {} failed with {}", store.bucket, status),
+            }),
+        }
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        content_type: Option<&str>,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, BlobError> {
+        let url = match key {
+            "" => Url::parse(&format!("{}/{}", self.endpoint, self.bucket))?,
+            key => Url::parse(&format!("{}/{}/{}", self.endpoint, self.bucket, key))?,
+        };
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => {
+                return Err(BlobError {
+                    message: format!("Invalid S3 endpoint: {}", self.endpoint),
+                })
+            }
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let amz_date = format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+            now.year(),
+            now.month() as u8,
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let mut headers = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(content_type) = content_type {
+            headers.insert(0, ("content-type", content_type));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let signature = signature(
+            &self.secret_key,
+            &self.region,
+            &amz_date,
+            method.as_str(),
+            url.path(),
+            &headers,
+            &payload_hash,
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}/{}/s3/aws4_request, SignedHeaders={}, Signature={}",
+            self.access_key,
+            &amz_date[..8],
+            self.region,
+            signed_headers,
+            signature
+        );
+
+        let mut request = self
+            .http
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash.as_str())
+            .header("x-amz-date", amz_date.as_str())
+            .header(AUTHORIZATION, authorization);
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        Ok(request.body(body).send().await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> Result<(), BlobError> {
+        let response = self
+            .request(Method::PUT, key, Some(content_type), data)
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(BlobError {
+                message: format!("Storing blob {} failed with {}", key, status),
+            }),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        let response = self.request(Method::GET, key, None, Vec::new()).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+            status => Err(BlobError {
+                message: format!("Loading blob {} failed with {}", key, status),
+            }),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        let response = self.request(Method::DELETE, key, None, Vec::new()).await?;
+        match response.status() {
+            status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            status => Err(BlobError {
+                message: format!("Deleting blob {} failed with {}", key, status),
+            }),
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The AWS Signature Version 4 signature of a request to S3. `headers` must be lowercase, sorted
+/// by name, and include every signed header.
+fn signature(
+    secret_key: &str,
+    region: &str,
+    amz_date: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+) -> String {
+    let date = &amz_date[..8];
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method, path, canonical_headers, signed_headers, payload_hash
+    );
+
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, "s3");
+    let key = hmac(&key, "aws4_request");
+    hex::encode(hmac(&key, &string_to_sign))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::parallel;
+
+    /// The "GET Object" example from the AWS Signature Version 4 documentation
+    #[test]
+    #[parallel]
+    fn test_signature() {
+        let payload_hash = hex::encode(Sha256::digest(b""));
+        let signature = signature(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "20130524T000000Z",
+            "GET",
+            "/test.txt",
+            &[
+                ("host", "examplebucket.s3.amazonaws.com"),
+                ("range", "bytes=0-9"),
+                ("x-amz-content-sha256", payload_hash.as_str()),
+                ("x-amz-date", "20130524T000000Z"),
+            ],
+            &payload_hash,
+        );
+        assert_eq!(
+            signature,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[actix_rt::test]
+    #[parallel]
+    async fn test_local_blob_store() {
+        let root = std::env::temp_dir().join("syntropic-blob-test");
+        let store = LocalBlobStore::new(root);
+        store
+            .put("key", "text/plain", b"Hello, world!".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("key").await.unwrap(),
+            Some(b"Hello, world!".to_vec())
+        );
+        store.delete("key").await.unwrap();
+        assert_eq!(store.get("key").await.unwrap(), None);
+        store.delete("key").await.unwrap();
+    }
+}
@@ -1,34 +1,48 @@
 use crate::amqp::Exchange;
-use crate::models::message::Message;
-use crate::schema::message as message_schema;
-use crate::Context;
-use actix_rt::spawn;
+use crate::models::audit_event::{AuditEvent, AuditEventFilter};
+use crate::models::flagged_message::FlaggedMessage;
+use crate::models::incoming_webhook::{CreatedIncomingWebhook, IncomingWebhook};
+use crate::models::message::{DeletedMessage, Message, INVALID_ATTACHMENTS};
+use crate::models::message_change::{MessageChange, SyncCursor, SyncResult};
+use crate::models::report::{Report, ReportConnection, ReportStatus};
+use crate::models::retention::RetentionStatus;
+use crate::models::scheduled_message::ScheduledMessage;
+use crate::models::webhook::{Webhook, EVENTS};
+use crate::{retention_policy, Context};
 use base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use base64::Engine;
-use diesel::prelude::*;
 use futures::stream::empty;
 use futures::Stream;
 use juniper::{futures, FieldResult};
 use std::pin::Pin;
+use time::{Duration, OffsetDateTime};
+
+/// The most changes returned by one sync
+const SYNC_LIMIT: i64 = 500;
+
+/// The most reports returned per page
+const REPORTS_LIMIT: i32 = 100;
+
+/// The most audit events returned per page
+const AUDIT_EVENTS_LIMIT: i32 = 100;
 
 pub struct Query;
 pub struct Mutation;
 pub struct Subscription;
 
+/// Fail unless the request was made with the admin token
+fn require_admin(context: &Context) -> FieldResult<()> {
+    if !context.admin {
+        return Err("Admin access required".into());
+    }
+    Ok(())
+}
+
 #[juniper::graphql_object(Context = crate::Context)]
 impl Query {
     /// A list of all messages
     pub async fn messages(context: &Context) -> FieldResult<Vec<Message>> {
-        let client = context.pool.get().await?;
-        let results: Vec<Message> = client
-            .interact(|client| {
-                message_schema::table
-                    .order(message_schema::id.desc())
-                    .load::<Message>(client)
-            })
-            .await??;
-
-        Ok(results)
+        Ok(Message::list(&context.pool, None, None).await?)
     }
 
     /// The message with the given ID
@@ -38,37 +52,293 @@ impl Query {
             .load(BASE64_URL_SAFE_NO_PAD.decode(id)?)
             .await)
     }
+
+    /// The changes to messages since the cursor returned by a previous sync. Reconnecting
+    /// clients should sync rather than refetch every message. Without a cursor, or with one
+    /// that is too old, `resync` is set and the client should refetch all messages.
+    pub async fn sync(context: &Context, since: Option<String>) -> FieldResult<SyncResult> {
+        let since = since
+            .map(|since| SyncCursor::parse(&since).ok_or("Invalid cursor"))
+            .transpose()?;
+        Ok(MessageChange::since(&context.pool, since, SYNC_LIMIT).await?)
+    }
+
+    /// Messages that are scheduled but not yet sent, soonest first. Requires the admin token.
+    pub async fn scheduled_messages(context: &Context) -> FieldResult<Vec<ScheduledMessage>> {
+        require_admin(context)?;
+        Ok(ScheduledMessage::list(&context.pool).await?)
+    }
+
+    /// The message retention policy and how many messages it has yet to archive
+    pub async fn retention_status(context: &Context) -> FieldResult<RetentionStatus> {
+        Ok(RetentionStatus::load(&context.pool, retention_policy()).await?)
+    }
+
+    /// Messages that moderation filters flagged for review, oldest first. Requires the admin
+    /// token.
+    pub async fn flagged_messages(context: &Context) -> FieldResult<Vec<FlaggedMessage>> {
+        require_admin(context)?;
+        Ok(FlaggedMessage::list(&context.pool).await?)
+    }
+
+    /// Reports of messages, oldest first, optionally only those with the given status. Pass the
+    /// previous page's `endCursor` as `after` to get the next page. Requires the admin token.
+    pub async fn reports(
+        context: &Context,
+        status: Option<ReportStatus>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> FieldResult<ReportConnection> {
+        require_admin(context)?;
+        let after = after
+            .map(|after| BASE64_URL_SAFE_NO_PAD.decode(after))
+            .transpose()?;
+        let first = match first {
+            Some(first) if !(1..=REPORTS_LIMIT).contains(&first) => {
+                return Err(format!("first must be between 1 and {}", REPORTS_LIMIT).into())
+            }
+            first => first.unwrap_or(REPORTS_LIMIT),
+        };
+        Ok(Report::list(&context.pool, status, after, first.into()).await?)
+    }
+
+    /// Privileged actions, newest first. Pass the last event's `id` as `after` to get the next
+    /// page. The whole log can be exported as NDJSON from `/api/v1/audit-events/export`.
+    /// Requires the admin token.
+    pub async fn audit_events(
+        context: &Context,
+        filter: Option<AuditEventFilter>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> FieldResult<Vec<AuditEvent>> {
+        require_admin(context)?;
+        let after = after
+            .map(|after| after.parse::<i64>())
+            .transpose()
+            .map_err(|_| "Invalid cursor")?;
+        let first = match first {
+            Some(first) if !(1..=AUDIT_EVENTS_LIMIT).contains(&first) => {
+                return Err(format!("first must be between 1 and {}", AUDIT_EVENTS_LIMIT).into())
+            }
+            first => first.unwrap_or(AUDIT_EVENTS_LIMIT),
+        };
+        Ok(AuditEvent::list(
+            &context.pool,
+            filter.unwrap_or_default(),
+            after,
+            first.into(),
+        )
+        .await?)
+    }
+
+    /// All webhooks, with their delivery logs. Requires the admin token.
+    pub async fn webhooks(context: &Context) -> FieldResult<Vec<Webhook>> {
+        require_admin(context)?;
+        Ok(Webhook::list(&context.pool).await?)
+    }
+
+    /// All incoming webhooks, without their URLs. Requires the admin token.
+    pub async fn incoming_webhooks(context: &Context) -> FieldResult<Vec<IncomingWebhook>> {
+        require_admin(context)?;
+        Ok(IncomingWebhook::list(&context.pool).await?)
+    }
 }
 
 #[juniper::graphql_object(Context = crate::Context)]
 impl Mutation {
-    /// Send a message
-    pub async fn send_message(context: &Context, body: String) -> FieldResult<Message> {
-        let message = Message::new(body);
-
-        let client = context.pool.get().await?;
-        let message: Message = client
-            .interact(|client| {
-                diesel::insert_into(message_schema::table)
-                    .values(message)
-                    .get_result(client)
-            })
-            .await??;
-
-        let client = context.amqp_client.clone();
-        let cloned_message = message.clone();
-
-        spawn(async move {
-            match client
-                .produce(cloned_message, Exchange::Messages, "message")
-                .await
-            {
-                Ok(_) => (),
-                Err(err) => println!("Error sending message: {}", err),
-            }
-        });
+    /// Send a message, with attachments uploaded to `/api/v1/attachments`. If `expiresIn` is
+    /// given, the message is deleted that many seconds after it is sent.
+    ///
+    /// Retrying with the same `clientMessageId` returns the message that was already sent
+    /// rather than sending it again, until that message expires. IDs are shared by all clients,
+    /// so they should be random, such as UUIDs.
+    pub async fn send_message(
+        context: &Context,
+        body: String,
+        attachments: Option<Vec<String>>,
+        expires_in: Option<i32>,
+        client_message_id: Option<String>,
+    ) -> FieldResult<Message> {
+        let attachments = attachments
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| BASE64_URL_SAFE_NO_PAD.decode(id))
+            .collect::<Result<_, _>>()?;
+        let expires_in = match expires_in {
+            Some(seconds) if seconds < 1 => return Err("expiresIn must be positive".into()),
+            expires_in => expires_in.map(|seconds| Duration::seconds(seconds.into())),
+        };
+        let body = context.moderator.moderate(body)?;
+        Message::send(
+            &context.pool,
+            &context.amqp_client,
+            body,
+            attachments,
+            expires_in,
+            client_message_id,
+        )
+        .await?
+        .ok_or_else(|| INVALID_ATTACHMENTS.into())
+    }
+
+    /// Schedule a message to be sent at a later time
+    pub async fn schedule_message(
+        context: &Context,
+        body: String,
+        send_at: OffsetDateTime,
+    ) -> FieldResult<ScheduledMessage> {
+        if send_at <= OffsetDateTime::now_utc() {
+            return Err("sendAt must be in the future".into());
+        }
+        // Reject early so the sender finds out now, but store the body as entered, since it is
+        // moderated again when it is sent
+        context.moderator.moderate(body.clone())?;
+        Ok(ScheduledMessage::create(&context.pool, ScheduledMessage::new(body, send_at)).await?)
+    }
+
+    /// Cancel a scheduled message, returning whether it had not been sent yet. Requires the admin
+    /// token.
+    pub async fn cancel_scheduled_message(context: &Context, id: String) -> FieldResult<bool> {
+        require_admin(context)?;
+        Ok(ScheduledMessage::cancel(
+            &context.pool,
+            &context.actor,
+            BASE64_URL_SAFE_NO_PAD.decode(id)?,
+        )
+        .await?)
+    }
+
+    /// Remove a message from the review queue, keeping the message, and return whether it was
+    /// in the queue. Requires the admin token.
+    pub async fn dismiss_flagged_message(context: &Context, id: String) -> FieldResult<bool> {
+        require_admin(context)?;
+        Ok(FlaggedMessage::dismiss(
+            &context.pool,
+            &context.actor,
+            BASE64_URL_SAFE_NO_PAD.decode(id)?,
+        )
+        .await?)
+    }
+
+    /// Delete a flagged message, removing it from the review queue, and return whether it
+    /// still existed. Requires the admin token.
+    pub async fn remove_flagged_message(context: &Context, id: String) -> FieldResult<bool> {
+        require_admin(context)?;
+        Ok(FlaggedMessage::remove(
+            &context.pool,
+            &context.amqp_client,
+            context.blob_store.as_ref(),
+            &context.actor,
+            BASE64_URL_SAFE_NO_PAD.decode(id)?,
+        )
+        .await?)
+    }
+
+    /// Report a message to moderators. Returns `null` if the message does not exist.
+    pub async fn report_message(
+        context: &Context,
+        id: String,
+        reason: String,
+    ) -> FieldResult<Option<Report>> {
+        if reason.trim().is_empty() {
+            return Err("reason must not be empty".into());
+        }
+        let report = Report::new(BASE64_URL_SAFE_NO_PAD.decode(id)?, reason);
+        Ok(Report::create(&context.pool, &context.amqp_client, report).await?)
+    }
+
+    /// Close an open report, keeping the message. Returns the report, or `null` if it was not
+    /// open. Requires the admin token.
+    pub async fn dismiss_report(context: &Context, id: String) -> FieldResult<Option<Report>> {
+        require_admin(context)?;
+        Ok(Report::dismiss(
+            &context.pool,
+            &context.amqp_client,
+            &context.actor,
+            BASE64_URL_SAFE_NO_PAD.decode(id)?,
+        )
+        .await?)
+    }
+
+    /// Delete a reported message, closing every open report of it. Returns the report, or
+    /// `null` if it was not open. Requires the admin token.
+    pub async fn delete_reported_message(
+        context: &Context,
+        id: String,
+    ) -> FieldResult<Option<Report>> {
+        require_admin(context)?;
+        Ok(Report::delete_message(
+            &context.pool,
+            &context.amqp_client,
+            context.blob_store.as_ref(),
+            &context.actor,
+            BASE64_URL_SAFE_NO_PAD.decode(id)?,
+        )
+        .await?)
+    }
+
+    /// Register a webhook. Each subscribed event is POSTed to `url` as JSON, signed with
+    /// `secret` in the `X-Syntropic-Signature` header. Events can arrive more than once, with the
+    /// same `X-Syntropic-Delivery` header each time. An empty list of events subscribes to all
+    /// events. Requires the admin token.
+    pub async fn create_webhook(
+        context: &Context,
+        url: String,
+        secret: String,
+        events: Option<Vec<String>>,
+    ) -> FieldResult<Webhook> {
+        require_admin(context)?;
+        match reqwest::Url::parse(&url) {
+            Ok(parsed) if ["http", "https"].contains(&parsed.scheme()) => (),
+            _ => return Err(format!("Invalid webhook URL: {}", url).into()),
+        }
+        let events = events.unwrap_or_default();
+        if let Some(event) = events
+            .iter()
+            .find(|event| !EVENTS.contains(&event.as_str()))
+        {
+            return Err(format!("Unknown event: {}", event).into());
+        }
 
-        Ok(message)
+        Ok(Webhook::create(
+            &context.pool,
+            &context.actor,
+            Webhook::new(url, secret, events),
+        )
+        .await?)
+    }
+
+    /// Delete a webhook, returning whether it existed. Requires the admin token.
+    pub async fn delete_webhook(context: &Context, id: String) -> FieldResult<bool> {
+        require_admin(context)?;
+        Ok(Webhook::delete(
+            &context.pool,
+            &context.actor,
+            BASE64_URL_SAFE_NO_PAD.decode(id)?,
+        )
+        .await?)
+    }
+
+    /// Create an incoming webhook that external tools can POST messages to. Its URL is only
+    /// returned here. Requires the admin token.
+    pub async fn create_incoming_webhook(
+        context: &Context,
+        name: String,
+    ) -> FieldResult<CreatedIncomingWebhook> {
+        require_admin(context)?;
+        Ok(IncomingWebhook::create(&context.pool, &context.actor, name).await?)
+    }
+
+    /// Delete an incoming webhook, revoking its URL, and return whether it existed. Requires the
+    /// admin token.
+    pub async fn delete_incoming_webhook(context: &Context, id: String) -> FieldResult<bool> {
+        require_admin(context)?;
+        Ok(IncomingWebhook::delete(
+            &context.pool,
+            &context.actor,
+            BASE64_URL_SAFE_NO_PAD.decode(id)?,
+        )
+        .await?)
     }
 }
 
@@ -90,4 +360,41 @@ impl Subscription {
             }
         }
     }
+
+    /// Messages that were deleted, such as when they expired
+    pub async fn message_deleted(
+        context: &Context,
+    ) -> Pin<Box<dyn Stream<Item = DeletedMessage> + Send>> {
+        let stream = context
+            .amqp_client
+            .clone()
+            .consume::<DeletedMessage>(Exchange::Messages, "message.deleted")
+            .await;
+        match stream {
+            Ok(stream) => Box::pin(stream),
+            Err(e) => {
+                println!("Error consuming message deletions: {}", e);
+                Box::pin(empty())
+            }
+        }
+    }
+
+    /// Reports as they are made and resolved, for moderators. Requires the admin token.
+    pub async fn report_updated(
+        context: &Context,
+    ) -> FieldResult<Pin<Box<dyn Stream<Item = Report> + Send>>> {
+        require_admin(context)?;
+        let stream = context
+            .amqp_client
+            .clone()
+            .consume::<Report>(Exchange::Moderation, "report.*")
+            .await;
+        Ok(match stream {
+            Ok(stream) => Box::pin(stream),
+            Err(e) => {
+                println!("Error consuming reports: {}", e);
+                Box::pin(empty())
+            }
+        })
+    }
 }
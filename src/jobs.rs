@@ -0,0 +1,130 @@
+use crate::amqp::AmqpClient;
+use crate::blob::BlobStore;
+use crate::models::attachment::Attachment;
+use crate::models::message::Message;
+use crate::models::message_change::MessageChange;
+use crate::models::retention::MessageArchive;
+use crate::models::scheduled_message::ScheduledMessage;
+use crate::moderation::Moderator;
+use crate::shutdown::Shutdown;
+use deadpool_diesel::postgres::Pool;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, Interval};
+
+const SCHEDULED_MESSAGE_INTERVAL: Duration = Duration::from_secs(1);
+const EXPIRED_MESSAGE_INTERVAL: Duration = Duration::from_secs(10);
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(60);
+const UNUSED_ATTACHMENT_INTERVAL: Duration = Duration::from_secs(60);
+const CHANGE_LOG_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Wait for the next tick, returning false instead if shutdown starts first
+async fn tick(interval: &mut Interval, shutdown: &Shutdown) -> bool {
+    tokio::select! {
+        _ = interval.tick() => true,
+        _ = shutdown.started() => false,
+    }
+}
+
+/// Send scheduled messages as they become due, until shutdown starts
+pub async fn send_scheduled_messages(
+    pool: Pool,
+    amqp_client: AmqpClient,
+    moderator: Arc<Moderator>,
+    shutdown: Shutdown,
+) {
+    let mut interval = interval(SCHEDULED_MESSAGE_INTERVAL);
+    while tick(&mut interval, &shutdown).await {
+        if let Err(err) = ScheduledMessage::send_due(&pool, &amqp_client, moderator.clone()).await {
+            println!("Error sending scheduled messages: {}", err);
+        }
+    }
+}
+
+/// Delete messages once they expire. Expired messages are already hidden from queries, so this
+/// only needs to run often enough to keep the table and blob store from growing.
+pub async fn delete_expired_messages(
+    pool: Pool,
+    amqp_client: AmqpClient,
+    blob_store: Arc<dyn BlobStore>,
+    shutdown: Shutdown,
+) {
+    let mut interval = interval(EXPIRED_MESSAGE_INTERVAL);
+    while tick(&mut interval, &shutdown).await {
+        if let Err(err) = Message::delete_expired(&pool, &amqp_client, blob_store.as_ref()).await {
+            println!("Error deleting expired messages: {}", err);
+        }
+    }
+}
+
+/// Archive and delete messages that have outlived the retention policy, working through any
+/// backlog a batch at a time. Does nothing if no policy is configured.
+pub async fn archive_messages(
+    pool: Pool,
+    amqp_client: AmqpClient,
+    blob_store: Arc<dyn BlobStore>,
+    shutdown: Shutdown,
+) {
+    let policy = crate::retention_policy();
+    if !policy.is_enabled() {
+        return;
+    }
+    let dir = PathBuf::from(crate::archive_path());
+
+    let mut interval = interval(ARCHIVE_INTERVAL);
+    while tick(&mut interval, &shutdown).await {
+        while !shutdown.has_started() {
+            match MessageArchive::archive_expired(
+                &pool,
+                &amqp_client,
+                blob_store.as_ref(),
+                policy,
+                dir.clone(),
+            )
+            .await
+            {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) => {
+                    println!("Error archiving messages: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Delete attachments that were uploaded but never sent, along with their contents, working
+/// through any backlog a batch at a time
+pub async fn delete_unused_attachments(
+    pool: Pool,
+    blob_store: Arc<dyn BlobStore>,
+    shutdown: Shutdown,
+) {
+    let max_age = crate::unused_attachment_max_age();
+    let mut interval = interval(UNUSED_ATTACHMENT_INTERVAL);
+    while tick(&mut interval, &shutdown).await {
+        while !shutdown.has_started() {
+            match Attachment::delete_unused(&pool, blob_store.as_ref(), max_age).await {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) => {
+                    println!("Error deleting unused attachments: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Delete changes that are too old for clients to sync from
+pub async fn compact_change_log(pool: Pool, shutdown: Shutdown) {
+    let max_age = crate::change_log_max_age();
+    let mut interval = interval(CHANGE_LOG_INTERVAL);
+    while tick(&mut interval, &shutdown).await {
+        if let Err(err) = MessageChange::compact(&pool, max_age).await {
+            println!("Error compacting change log: {}", err);
+        }
+    }
+}
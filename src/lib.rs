@@ -1,8 +1,14 @@
 use crate::amqp::AmqpClient;
+use crate::blob::{BlobStore, LocalBlobStore, S3BlobStore};
 use crate::graphql::Subscription;
+use crate::models::audit_event::{new_request_id, Actor};
 use crate::models::message::{Message, MessageLoader};
+use crate::models::retention::RetentionPolicy;
+use crate::moderation::Moderator;
+use crate::shutdown::{close_on_shutdown, Shutdown};
+use actix_web::http::header::{HeaderName, HeaderValue, AUTHORIZATION};
 use actix_web::web::{resource, Data, ServiceConfig};
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 use deadpool::managed::Manager as _;
 use deadpool_diesel::postgres::Manager;
 use deadpool_diesel::postgres::Pool;
@@ -13,19 +19,30 @@ use juniper::RootNode;
 use juniper_actix::subscriptions::subscriptions_handler;
 use juniper_actix::{graphiql_handler, graphql_handler, playground_handler};
 use juniper_graphql_ws::ConnectionConfig;
+use sha2::{Digest, Sha256};
 use std::env::var;
 use std::sync::Arc;
+use std::time::Duration;
 
 mod protos {
     include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
-    pub use message::Message;
+    pub use message::{Attachment, DeletedMessage, Message, Report};
 }
 
+pub mod admin;
 pub mod amqp;
+pub mod blob;
 pub mod graphql;
+pub mod jobs;
+mod markdown;
 pub mod models;
+pub mod moderation;
+pub mod rest;
 mod schema;
-mod snowflake;
+pub mod shutdown;
+pub mod snowflake;
+pub mod sse;
+pub mod webhooks;
 
 fn db_url() -> String {
     var("DATABASE_URL")
@@ -36,13 +53,172 @@ fn amqp_url() -> String {
     var("AMQP_URL").unwrap_or("amqp://localhost:5672".to_string())
 }
 
+fn blob_store_kind() -> String {
+    var("BLOB_STORE").unwrap_or("local".to_string())
+}
+
+fn blob_path() -> String {
+    var("BLOB_PATH").unwrap_or("blobs".to_string())
+}
+
+fn s3_endpoint() -> String {
+    var("S3_ENDPOINT").unwrap_or("http://localhost:9000".to_string())
+}
+
+fn s3_bucket() -> String {
+    var("S3_BUCKET").unwrap_or("syntropic".to_string())
+}
+
+fn s3_region() -> String {
+    var("S3_REGION").unwrap_or("us-east-1".to_string())
+}
+
+fn s3_access_key() -> String {
+    var("S3_ACCESS_KEY").unwrap_or("minioadmin".to_string())
+}
+
+fn s3_secret_key() -> String {
+    var("S3_SECRET_KEY").unwrap_or("minioadmin".to_string())
+}
+
+/// The largest attachment that can be uploaded, in bytes
+fn max_attachment_size() -> usize {
+    var("MAX_ATTACHMENT_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(10 * 1024 * 1024)
+}
+
+/// How long an uploaded attachment is kept without being sent, one day by default
+fn unused_attachment_max_age() -> Duration {
+    Duration::from_secs(
+        var("UNUSED_ATTACHMENT_MAX_AGE")
+            .ok()
+            .and_then(|age| age.parse().ok())
+            .unwrap_or(24 * 60 * 60),
+    )
+}
+
+/// Messages older than this many seconds are archived, if set
+fn retention_max_age() -> Option<Duration> {
+    var("RETENTION_MAX_AGE")
+        .ok()
+        .and_then(|age| age.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Only this many of the newest messages are kept, if set
+fn retention_max_count() -> Option<i64> {
+    var("RETENTION_MAX_COUNT")
+        .ok()
+        .and_then(|count| count.parse().ok())
+}
+
+fn retention_policy() -> RetentionPolicy {
+    RetentionPolicy::new(retention_max_age(), retention_max_count())
+}
+
+/// Panic unless every configured max age reaches back to a time that can be represented, so
+/// that the server refuses to start rather than failing when the limit is first applied
+fn check_max_ages() {
+    let max_ages = [
+        ("RETENTION_MAX_AGE", retention_max_age()),
+        ("CHANGE_LOG_MAX_AGE", Some(change_log_max_age())),
+        (
+            "UNUSED_ATTACHMENT_MAX_AGE",
+            Some(unused_attachment_max_age()),
+        ),
+    ];
+    for (name, max_age) in max_ages {
+        if let Some(max_age) = max_age {
+            assert!(models::ago(max_age).is_some(), "{} is too large", name);
+        }
+    }
+}
+
+fn archive_path() -> String {
+    var("ARCHIVE_PATH").unwrap_or("archive".to_string())
+}
+
+/// How long changes are kept for clients to sync, 30 days by default
+fn change_log_max_age() -> Duration {
+    Duration::from_secs(
+        var("CHANGE_LOG_MAX_AGE")
+            .ok()
+            .and_then(|age| age.parse().ok())
+            .unwrap_or(30 * 24 * 60 * 60),
+    )
+}
+
+/// The path of the JSON file configuring moderation filters, if any
+fn moderation_config() -> Option<String> {
+    var("MODERATION_CONFIG").ok()
+}
+
+/// The bearer token that grants access to admin fields and endpoints, if admin access is
+/// enabled
+fn admin_token() -> Option<String> {
+    var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+fn shutdown_timeout() -> Duration {
+    Duration::from_secs(
+        var("SHUTDOWN_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(30),
+    )
+}
+
+/// The header that carries a request's ID, which is recorded in the audit log
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
 const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
 type Schema = RootNode<'static, Query, Mutation, Subscription>;
 
-type AppData = Data<(Pool, AmqpClient)>;
+pub type AppData = Data<(Pool, AmqpClient, Arc<dyn BlobStore>, Arc<Moderator>)>;
+
+pub async fn blob_store() -> Arc<dyn BlobStore> {
+    match blob_store_kind().as_str() {
+        "s3" => Arc::new(
+            S3BlobStore::connect(
+                s3_endpoint(),
+                s3_bucket(),
+                s3_region(),
+                s3_access_key(),
+                s3_secret_key(),
+            )
+            .await
+            .unwrap(),
+        ),
+        _ => Arc::new(LocalBlobStore::new(blob_path().into())),
+    }
+}
+
+async fn moderator() -> Arc<Moderator> {
+    match moderation_config() {
+        Some(path) => {
+            let config = tokio::fs::read_to_string(path).await.unwrap();
+            Arc::new(Moderator::from_config(&config).unwrap())
+        }
+        None => Arc::new(Moderator::default()),
+    }
+}
+
+/// A pool of database connections, without running migrations
+pub fn pool() -> Pool {
+    Pool::builder(Manager::new(db_url(), Runtime::Tokio1))
+        .build()
+        .unwrap()
+}
+
+pub async fn amqp_client() -> AmqpClient {
+    AmqpClient::new(amqp_url()).await
+}
 
 pub async fn data() -> AppData {
+    check_max_ages();
     let manager = Manager::new(db_url(), Runtime::Tokio1);
     let client = manager.create().await.unwrap();
     client
@@ -52,26 +228,47 @@ pub async fn data() -> AppData {
         .await
         .unwrap();
     let pool = Pool::builder(manager).build().unwrap();
-    let amqp_client = AmqpClient::new(amqp_url()).await;
-    Data::new((pool, amqp_client))
+    Data::new((
+        pool,
+        amqp_client().await,
+        blob_store().await,
+        moderator().await,
+    ))
 }
 
 pub struct Context {
     pub pool: Pool,
     pub message_loader: MessageLoader,
     pub amqp_client: AmqpClient,
+    pub blob_store: Arc<dyn BlobStore>,
+    pub moderator: Arc<Moderator>,
+    /// Who is making the request, for the audit log
+    pub actor: Actor,
+    /// Whether the request was made by an administrator, which admin fields require
+    pub admin: bool,
 }
 
 impl juniper::Context for Context {}
 
-impl From<AppData> for Context {
-    fn from(data: AppData) -> Self {
+impl Context {
+    /// The context for requests made by `actor`. Background jobs and tests act as
+    /// [`Actor::system`].
+    pub fn new(data: AppData, actor: Actor) -> Self {
         Self {
             pool: data.as_ref().0.clone(),
             message_loader: Message::loader(data.as_ref().0.clone()),
             amqp_client: data.as_ref().1.clone(),
+            blob_store: data.as_ref().2.clone(),
+            moderator: data.as_ref().3.clone(),
+            actor,
+            admin: false,
         }
     }
+
+    /// The same context, with admin access if `admin` is set
+    pub fn with_admin(self, admin: bool) -> Self {
+        Self { admin, ..self }
+    }
 }
 
 fn schema() -> Schema {
@@ -86,32 +283,86 @@ async fn playground_route() -> Result<HttpResponse, Error> {
     playground_handler("/graphql", Some("/subscriptions")).await
 }
 
+/// The client making a request, identified by its address until there are user accounts, and
+/// the request's ID, taken from its `X-Request-Id` header or generated. The address is the
+/// connection's peer rather than `Forwarded` or `X-Forwarded-For`, which any client can set.
+fn actor(req: &HttpRequest) -> Actor {
+    let name = req
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 200)
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id);
+    Actor::new(name, Some(request_id))
+}
+
+/// Whether the request carries the admin token in an `Authorization: Bearer` header
+fn is_admin(req: &HttpRequest) -> bool {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+    match (admin_token(), token) {
+        // Comparing digests rather than the tokens themselves reveals nothing about how much of
+        // a guess matches the token
+        (Some(expected), Some(token)) => {
+            Sha256::digest(expected.as_bytes()) == Sha256::digest(token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
 async fn graphql_route(
-    req: actix_web::HttpRequest,
+    req: HttpRequest,
     payload: web::Payload,
-    data: Data<(Pool, AmqpClient)>,
+    data: AppData,
 ) -> Result<HttpResponse, Error> {
-    graphql_handler(&schema(), &data.into(), req, payload).await
+    let context = Context::new(data, actor(&req)).with_admin(is_admin(&req));
+    let request_id = context.actor.request_id().map(HeaderValue::from_str);
+
+    let mut response = graphql_handler(&schema(), &context, req, payload).await?;
+    if let Some(Ok(request_id)) = request_id {
+        response.headers_mut().insert(REQUEST_ID, request_id);
+    }
+    Ok(response)
 }
 
 async fn subscriptions_route(
-    req: actix_web::HttpRequest,
+    req: HttpRequest,
     payload: web::Payload,
-    data: Data<(Pool, AmqpClient)>,
+    data: AppData,
 ) -> Result<HttpResponse, Error> {
-    let config = ConnectionConfig::new(data.into());
+    // graphql-ws also runs queries and mutations, which are audited like those over HTTP
+    let context = Context::new(data, actor(&req)).with_admin(is_admin(&req));
+    let config = ConnectionConfig::new(context);
     let config = config.with_keep_alive_interval(std::time::Duration::from_secs(15));
 
-    subscriptions_handler(req, payload, Arc::new(schema()), config).await
+    // The server registers its shutdown handle, so that sockets are closed when it stops
+    let shutdown = req.app_data::<Shutdown>().cloned();
+    let response = subscriptions_handler(req, payload, Arc::new(schema()), config).await?;
+    Ok(match shutdown {
+        Some(shutdown) => close_on_shutdown(response, shutdown),
+        None => response,
+    })
 }
 
 pub fn configure(cfg: &mut ServiceConfig) {
+    // Each worker loads changes for its own SSE connections
+    cfg.app_data(Data::new(sse::ChangeFeed::default()));
     cfg.service(
         resource("/graphql")
             .route(web::post().to(graphql_route))
             .route(web::get().to(graphql_route)),
     )
     .service(resource("/subscriptions").route(web::get().to(subscriptions_route)))
+    .service(resource("/events").route(web::get().to(sse::events_route)))
     .service(resource("/graphiql").route(web::get().to(graphiql_route)))
-    .service(resource("/playground").route(web::get().to(playground_route)));
+    .service(resource("/playground").route(web::get().to(playground_route)))
+    .service(web::scope("/api/v1").configure(rest::configure));
 }
@@ -1,14 +1,47 @@
 use actix_cors::Cors;
 use actix_web::{http::header, middleware, App, HttpServer};
-use syntropic_api::{configure, data};
+use syntropic_api::shutdown::Shutdown;
+use syntropic_api::{configure, data, jobs, shutdown, webhooks};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let data = data().await;
+    let app_data = data.clone();
 
+    let shutdown = Shutdown::new();
+    let tasks = vec![
+        actix_rt::spawn(webhooks::run(data.0.clone(), data.1.clone())),
+        actix_rt::spawn(jobs::send_scheduled_messages(
+            data.0.clone(),
+            data.1.clone(),
+            data.3.clone(),
+            shutdown.clone(),
+        )),
+        actix_rt::spawn(jobs::delete_expired_messages(
+            data.0.clone(),
+            data.1.clone(),
+            data.2.clone(),
+            shutdown.clone(),
+        )),
+        actix_rt::spawn(jobs::archive_messages(
+            data.0.clone(),
+            data.1.clone(),
+            data.2.clone(),
+            shutdown.clone(),
+        )),
+        actix_rt::spawn(jobs::delete_unused_attachments(
+            data.0.clone(),
+            data.2.clone(),
+            shutdown.clone(),
+        )),
+        actix_rt::spawn(jobs::compact_change_log(data.0.clone(), shutdown.clone())),
+    ];
+
+    let app_shutdown = shutdown.clone();
     let server = HttpServer::new(move || {
         App::new()
-            .app_data(data.clone())
+            .app_data(app_data.clone())
+            .app_data(app_shutdown.clone())
             .configure(configure)
             .wrap(
                 Cors::default()
@@ -20,7 +53,13 @@ async fn main() -> std::io::Result<()> {
             )
             .wrap(middleware::Compress::default())
             .wrap(middleware::Logger::default())
-    });
+    })
+    .disable_signals()
+    .shutdown_timeout(shutdown::server_timeout());
 
-    server.bind("localhost:8080").unwrap().run().await
+    let server = server.bind("localhost:8080").unwrap().run();
+    let graceful = actix_rt::spawn(shutdown::graceful(data, server.handle(), shutdown, tasks));
+    server.await?;
+    graceful.await.unwrap();
+    Ok(())
 }
@@ -0,0 +1,95 @@
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+const SAFE_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// Whether a link destination is relative or uses a scheme that cannot run script
+fn is_safe_url(url: &str) -> bool {
+    match url.find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c))) {
+        Some(end) if url[end..].starts_with(':') => SAFE_SCHEMES
+            .iter()
+            .any(|scheme| url[..end].eq_ignore_ascii_case(scheme)),
+        _ => true,
+    }
+}
+
+fn sanitize_url(url: CowStr) -> CowStr {
+    match is_safe_url(url.trim()) {
+        true => url,
+        false => CowStr::Borrowed("#"),
+    }
+}
+
+/// Parse a message body as Markdown, with the extensions it is rendered with
+pub(crate) fn parser(body: &str) -> Parser<'_, '_> {
+    Parser::new_ext(body, Options::ENABLE_STRIKETHROUGH)
+}
+
+/// Render a message body from Markdown to HTML that is safe to insert into a page.
+///
+/// Raw HTML in the body is escaped rather than passed through, and links and images with a
+/// scheme other than http, https or mailto are replaced with `#`.
+pub fn render(body: &str) -> String {
+    let parser = parser(body).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(link_type, url, title)) => {
+            Event::Start(Tag::Link(link_type, sanitize_url(url), title))
+        }
+        Event::Start(Tag::Image(link_type, url, title)) => {
+            Event::Start(Tag::Image(link_type, sanitize_url(url), title))
+        }
+        event => event,
+    });
+
+    let mut output = String::new();
+    html::push_html(&mut output, parser);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::parallel;
+
+    #[test]
+    #[parallel]
+    fn test_render() {
+        assert_eq!(
+            render("Hello, *world*!\n\n~~gone~~"),
+            "<p>Hello, <em>world</em>!</p>\n<p><del>gone</del></p>\n"
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn test_render_escapes_html() {
+        assert_eq!(
+            render("<script>alert(1)</script>"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+        assert_eq!(
+            render("a <img src=x onerror=alert(1)> b"),
+            "<p>a &lt;img src=x onerror=alert(1)&gt; b</p>\n"
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn test_render_sanitizes_links() {
+        assert_eq!(
+            render("[a](javascript:alert(1))"),
+            "<p><a href=\"#\">a</a></p>\n"
+        );
+        assert_eq!(
+            render("[a](JavaScript:alert(1))"),
+            "<p><a href=\"#\">a</a></p>\n"
+        );
+        assert_eq!(
+            render("[a](https://example.com)"),
+            "<p><a href=\"https://example.com\">a</a></p>\n"
+        );
+        assert_eq!(
+            render("[a](/relative)"),
+            "<p><a href=\"/relative\">a</a></p>\n"
+        );
+    }
+}
@@ -1 +1,39 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+pub mod attachment;
+pub mod audit_event;
+pub mod flagged_message;
+pub mod incoming_webhook;
 pub mod message;
+pub mod message_change;
+pub mod report;
+pub mod retention;
+pub mod scheduled_message;
+pub mod webhook;
+
+#[derive(Debug)]
+pub struct DatabaseError {
+    pub message: String,
+}
+
+impl Display for DatabaseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl<T: Error> From<T> for DatabaseError {
+    fn from(e: T) -> Self {
+        Self {
+            message: e.to_string(),
+        }
+    }
+}
+
+/// The time `age` ago, or `None` if that is before the earliest time that can be represented
+pub(crate) fn ago(age: Duration) -> Option<OffsetDateTime> {
+    OffsetDateTime::now_utc().checked_sub(time::Duration::try_from(age).ok()?)
+}
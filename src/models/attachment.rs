@@ -0,0 +1,271 @@
+use crate::amqp::AmqpError;
+use crate::blob::BlobStore;
+use crate::models::{ago, DatabaseError};
+use crate::protos::Attachment as AttachmentProto;
+use crate::schema::attachment as attachment_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::prelude::*;
+use diesel::{Insertable, PgConnection, Queryable};
+use protobuf::SpecialFields;
+use serde::ser::{Error as _, SerializeStruct};
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// The most unused attachments deleted at a time
+const UNUSED_BATCH_SIZE: i64 = 100;
+
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = attachment_schema)]
+pub struct Attachment {
+    id: Vec<u8>,
+    message_id: Option<Vec<u8>>,
+    timestamp: OffsetDateTime,
+    filename: Option<String>,
+    content_type: String,
+    size: i64,
+    checksum: String,
+}
+
+impl Attachment {
+    /// A new attachment that is not yet part of a message
+    pub fn new(filename: Option<String>, content_type: String, data: &[u8]) -> Self {
+        let timestamp = time_in_millis();
+        let id = snowflake(timestamp);
+        Self {
+            id,
+            message_id: None,
+            timestamp,
+            filename,
+            content_type,
+            size: data.len() as i64,
+            checksum: hex::encode(Sha256::digest(data)),
+        }
+    }
+
+    /// The key the attachment's contents are stored under in the blob store
+    pub fn key(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    pub fn mime_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub async fn create(pool: &Pool, attachment: Attachment) -> Result<Attachment, DatabaseError> {
+        let client = pool.get().await?;
+        let attachment = client
+            .interact(|client| {
+                diesel::insert_into(attachment_schema::table)
+                    .values(attachment)
+                    .get_result(client)
+            })
+            .await??;
+
+        Ok(attachment)
+    }
+
+    /// The attachment with the given ID, if it has been sent with a message. Attachments that
+    /// have only been uploaded are not served, so that uploads cannot be shared without
+    /// sending them.
+    pub async fn find(pool: &Pool, id: Vec<u8>) -> Result<Option<Attachment>, DatabaseError> {
+        let client = pool.get().await?;
+        let result = client
+            .interact(|client| {
+                attachment_schema::table
+                    .find(id)
+                    .filter(attachment_schema::message_id.is_not_null())
+                    .first::<Attachment>(client)
+                    .optional()
+            })
+            .await??;
+
+        Ok(result)
+    }
+
+    /// Delete a batch of attachments that were uploaded more than `max_age` ago but never sent
+    /// with a message, then delete their contents, returning how many were deleted. Attachments
+    /// being sent are locked, so they are skipped.
+    pub async fn delete_unused(
+        pool: &Pool,
+        blob_store: &dyn BlobStore,
+        max_age: Duration,
+    ) -> Result<usize, DatabaseError> {
+        // Nothing is older than the earliest time that can be represented
+        let oldest_kept = match ago(max_age) {
+            Some(oldest_kept) => oldest_kept,
+            None => return Ok(0),
+        };
+        let client = pool.get().await?;
+        let deleted = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let ids = attachment_schema::table
+                        .select(attachment_schema::id)
+                        .filter(attachment_schema::message_id.is_null())
+                        .filter(attachment_schema::timestamp.lt(oldest_kept))
+                        .limit(UNUSED_BATCH_SIZE)
+                        .for_update()
+                        .skip_locked()
+                        .load::<Vec<u8>>(client)?;
+                    diesel::delete(
+                        attachment_schema::table.filter(attachment_schema::id.eq_any(ids)),
+                    )
+                    .get_results::<Attachment>(client)
+                })
+            })
+            .await??;
+
+        for attachment in &deleted {
+            if let Err(err) = blob_store.delete(&attachment.key()).await {
+                println!("Error deleting attachment {}: {}", attachment.key(), err);
+            }
+        }
+        Ok(deleted.len())
+    }
+
+    /// Lock the given attachments until the transaction ends, returning whether they all exist
+    /// and are unused. Should be run in a transaction before [`Attachment::link`], so that the
+    /// attachments cannot be used by another message in between.
+    pub(crate) fn lock_unused(client: &mut PgConnection, ids: &[Vec<u8>]) -> QueryResult<bool> {
+        if ids.is_empty() {
+            return Ok(true);
+        }
+        let unused = attachment_schema::table
+            .select(attachment_schema::id)
+            .filter(attachment_schema::id.eq_any(ids.to_vec()))
+            .filter(attachment_schema::message_id.is_null())
+            .for_update()
+            .load::<Vec<u8>>(client)?;
+        Ok(unused.len() == ids.len())
+    }
+
+    /// Add unused attachments to a message, returning how many were added
+    pub(crate) fn link(
+        client: &mut PgConnection,
+        message_id: &[u8],
+        ids: &[Vec<u8>],
+    ) -> QueryResult<usize> {
+        diesel::update(
+            attachment_schema::table
+                .filter(attachment_schema::id.eq_any(ids.to_vec()))
+                .filter(attachment_schema::message_id.is_null()),
+        )
+        .set(attachment_schema::message_id.eq(message_id.to_vec()))
+        .execute(client)
+    }
+
+    /// The attachments of each of the given messages, oldest first
+    pub(crate) fn for_messages(
+        client: &mut PgConnection,
+        message_ids: Vec<Vec<u8>>,
+    ) -> QueryResult<HashMap<Vec<u8>, Vec<Attachment>>> {
+        let results = attachment_schema::table
+            .filter(attachment_schema::message_id.eq_any(message_ids))
+            .order(attachment_schema::id.asc())
+            .load::<Attachment>(client)?;
+
+        let mut attachments: HashMap<Vec<u8>, Vec<Attachment>> = HashMap::new();
+        for result in results {
+            if let Some(message_id) = result.message_id.clone() {
+                attachments.entry(message_id).or_default().push(result);
+            }
+        }
+        Ok(attachments)
+    }
+
+    pub(crate) fn to_protobuf(&self) -> AttachmentProto {
+        AttachmentProto {
+            id: self.id.clone(),
+            filename: self.filename.clone().unwrap_or_default(),
+            content_type: self.content_type.clone(),
+            size: self.size as u64,
+            checksum: self.checksum.clone(),
+            timestamp: (self.timestamp.unix_timestamp_nanos() / 1_000_000) as u64,
+            special_fields: SpecialFields::new(),
+        }
+    }
+
+    pub(crate) fn from_protobuf(
+        attachment: &AttachmentProto,
+        message_id: &[u8],
+    ) -> Result<Self, AmqpError> {
+        Ok(Self {
+            id: attachment.id.to_vec(),
+            message_id: Some(message_id.to_vec()),
+            timestamp: OffsetDateTime::from_unix_timestamp_nanos(
+                attachment.timestamp as i128 * 1_000_000,
+            )?,
+            filename: Some(attachment.filename.to_string()).filter(|name| !name.is_empty()),
+            content_type: attachment.content_type.to_string(),
+            size: attachment.size as i64,
+            checksum: attachment.checksum.to_string(),
+        })
+    }
+}
+
+impl Serialize for Attachment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Attachment", 7)?;
+        state.serialize_field("id", &self.id())?;
+        state.serialize_field("filename", &self.filename)?;
+        state.serialize_field("contentType", &self.content_type)?;
+        state.serialize_field("size", &self.size)?;
+        state.serialize_field("checksum", &self.checksum)?;
+        state.serialize_field("url", &self.url())?;
+        state.serialize_field(
+            "timestamp",
+            &self.timestamp.format(&Rfc3339).map_err(S::Error::custom)?,
+        )?;
+        state.end()
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A file attached to a message
+impl Attachment {
+    /// The attachment's unique ID
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// The name of the uploaded file, if one was given
+    pub fn filename(&self) -> Option<String> {
+        self.filename.clone()
+    }
+
+    /// The MIME type of the file
+    pub fn content_type(&self) -> String {
+        self.content_type.clone()
+    }
+
+    /// The size of the file in bytes
+    pub fn size(&self) -> i32 {
+        i32::try_from(self.size).unwrap_or(i32::MAX)
+    }
+
+    /// The hex-encoded SHA-256 checksum of the file
+    pub fn checksum(&self) -> String {
+        self.checksum.clone()
+    }
+
+    /// The path the file can be downloaded from
+    pub fn url(&self) -> String {
+        format!("/api/v1/attachments/{}", self.id())
+    }
+
+    /// The time the file was uploaded
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+}
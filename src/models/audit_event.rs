@@ -0,0 +1,290 @@
+use crate::models::DatabaseError;
+use crate::schema::audit_event as audit_event_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::{PgConnection, Queryable};
+use juniper::futures::stream::{iter, unfold};
+use juniper::futures::{Stream, StreamExt};
+use serde::ser::{Error as _, SerializeStruct};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How many events are loaded at a time while exporting
+const EXPORT_BATCH_SIZE: i64 = 500;
+
+/// Who performed an action, and in which request
+#[derive(Clone)]
+pub struct Actor {
+    name: String,
+    request_id: Option<String>,
+}
+
+impl Actor {
+    pub fn new(name: String, request_id: Option<String>) -> Self {
+        Self { name, request_id }
+    }
+
+    /// The server itself, such as a background job
+    pub fn system() -> Self {
+        Self::new("system".to_string(), None)
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+}
+
+/// A new request ID, for requests that do not bring their own
+pub fn new_request_id() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(snowflake(time_in_millis()))
+}
+
+/// A record of a privileged action. The table is append-only: a trigger rejects updates and
+/// deletes.
+#[derive(Queryable, Clone)]
+pub struct AuditEvent {
+    /// Assigned by the database when the event is recorded
+    seq: i64,
+    timestamp: OffsetDateTime,
+    actor: String,
+    action: String,
+    target_type: String,
+    target_id: String,
+    before: Option<Value>,
+    after: Option<Value>,
+    request_id: Option<String>,
+}
+
+/// Which audit events to return. Every given field must match.
+#[derive(Clone, Default, juniper::GraphQLInputObject)]
+pub struct AuditEventFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    /// Only events at or after this time
+    pub since: Option<OffsetDateTime>,
+    /// Only events before this time
+    pub until: Option<OffsetDateTime>,
+}
+
+impl AuditEventFilter {
+    fn query(self) -> audit_event_schema::BoxedQuery<'static, Pg> {
+        let mut query = audit_event_schema::table.into_boxed();
+        if let Some(actor) = self.actor {
+            query = query.filter(audit_event_schema::actor.eq(actor));
+        }
+        if let Some(action) = self.action {
+            query = query.filter(audit_event_schema::action.eq(action));
+        }
+        if let Some(target_type) = self.target_type {
+            query = query.filter(audit_event_schema::target_type.eq(target_type));
+        }
+        if let Some(target_id) = self.target_id {
+            query = query.filter(audit_event_schema::target_id.eq(target_id));
+        }
+        if let Some(since) = self.since {
+            query = query.filter(audit_event_schema::timestamp.ge(since));
+        }
+        if let Some(until) = self.until {
+            query = query.filter(audit_event_schema::timestamp.lt(until));
+        }
+        query
+    }
+}
+
+impl AuditEvent {
+    /// An event of `actor` performing `action` on the target, such as `webhook.delete` on the
+    /// `webhook` with the given ID, with the target's state before and after the action
+    pub fn new(
+        actor: &Actor,
+        action: &str,
+        target_type: &str,
+        target_id: &[u8],
+        before: Option<Value>,
+        after: Option<Value>,
+    ) -> Self {
+        Self {
+            seq: 0,
+            timestamp: OffsetDateTime::now_utc(),
+            actor: actor.name.clone(),
+            action: action.to_string(),
+            target_type: target_type.to_string(),
+            target_id: BASE64_URL_SAFE_NO_PAD.encode(target_id),
+            before,
+            after,
+            request_id: actor.request_id.clone(),
+        }
+    }
+
+    /// Append events to the log. Should be run in the transaction that performs the actions, so
+    /// that an action is never committed without its record.
+    pub(crate) fn record(client: &mut PgConnection, events: Vec<AuditEvent>) -> QueryResult<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let rows: Vec<_> = events
+            .into_iter()
+            .map(|event| {
+                (
+                    audit_event_schema::timestamp.eq(event.timestamp),
+                    audit_event_schema::actor.eq(event.actor),
+                    audit_event_schema::action.eq(event.action),
+                    audit_event_schema::target_type.eq(event.target_type),
+                    audit_event_schema::target_id.eq(event.target_id),
+                    audit_event_schema::before.eq(event.before),
+                    audit_event_schema::after.eq(event.after),
+                    audit_event_schema::request_id.eq(event.request_id),
+                )
+            })
+            .collect();
+        diesel::insert_into(audit_event_schema::table)
+            .values(rows)
+            .execute(client)?;
+        Ok(())
+    }
+
+    /// Up to `limit` matching events, newest first, starting after the event with ID `after`
+    pub async fn list(
+        pool: &Pool,
+        filter: AuditEventFilter,
+        after: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(move |client| {
+                let mut query = filter
+                    .query()
+                    .order(audit_event_schema::seq.desc())
+                    .limit(limit);
+                if let Some(after) = after {
+                    query = query.filter(audit_event_schema::seq.lt(after));
+                }
+                query.load::<AuditEvent>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+
+    /// Every matching event, oldest first, loaded in batches as the stream is read
+    pub fn export(
+        pool: Pool,
+        filter: AuditEventFilter,
+    ) -> impl Stream<Item = Result<AuditEvent, DatabaseError>> {
+        let batches = unfold(Some(0), move |after| {
+            let pool = pool.clone();
+            let filter = filter.clone();
+            async move {
+                let after = after?;
+                let batch = AuditEvent::batch(&pool, filter, after).await;
+                let next = match &batch {
+                    Ok(events) if events.len() as i64 == EXPORT_BATCH_SIZE => {
+                        events.last().map(|event| event.seq)
+                    }
+                    _ => None,
+                };
+                Some((batch, next))
+            }
+        });
+        batches.flat_map(|batch| match batch {
+            Ok(events) => iter(events.into_iter().map(Ok).collect::<Vec<_>>()),
+            Err(e) => iter(vec![Err(e)]),
+        })
+    }
+
+    async fn batch(
+        pool: &Pool,
+        filter: AuditEventFilter,
+        after: i64,
+    ) -> Result<Vec<AuditEvent>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(move |client| {
+                filter
+                    .query()
+                    .filter(audit_event_schema::seq.gt(after))
+                    .order(audit_event_schema::seq.asc())
+                    .limit(EXPORT_BATCH_SIZE)
+                    .load::<AuditEvent>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+}
+
+impl Serialize for AuditEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("AuditEvent", 9)?;
+        state.serialize_field("id", &self.seq.to_string())?;
+        state.serialize_field(
+            "timestamp",
+            &self.timestamp.format(&Rfc3339).map_err(S::Error::custom)?,
+        )?;
+        state.serialize_field("actor", &self.actor)?;
+        state.serialize_field("action", &self.action)?;
+        state.serialize_field("targetType", &self.target_type)?;
+        state.serialize_field("targetId", &self.target_id)?;
+        state.serialize_field("before", &self.before)?;
+        state.serialize_field("after", &self.after)?;
+        state.serialize_field("requestId", &self.request_id)?;
+        state.end()
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A record of a privileged action
+impl AuditEvent {
+    /// The event's unique ID, which increases in the order events were recorded
+    pub fn id(&self) -> String {
+        self.seq.to_string()
+    }
+
+    /// The time of the action
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+
+    /// Who performed the action: the client's address, or `system` for the server itself
+    pub fn actor(&self) -> String {
+        self.actor.clone()
+    }
+
+    /// What was done, such as `webhook.delete`
+    pub fn action(&self) -> String {
+        self.action.clone()
+    }
+
+    /// The kind of object acted on, such as `webhook`
+    pub fn target_type(&self) -> String {
+        self.target_type.clone()
+    }
+
+    /// The ID of the object acted on
+    pub fn target_id(&self) -> String {
+        self.target_id.clone()
+    }
+
+    /// The object before the action as JSON, if it existed
+    pub fn before(&self) -> Option<String> {
+        self.before.as_ref().map(Value::to_string)
+    }
+
+    /// The object after the action as JSON, if it still exists
+    pub fn after(&self) -> Option<String> {
+        self.after.as_ref().map(Value::to_string)
+    }
+
+    /// The ID of the request that performed the action, as sent in its `X-Request-Id` header
+    pub fn request_id(&self) -> Option<String> {
+        self.request_id.clone()
+    }
+}
@@ -0,0 +1,168 @@
+use crate::amqp::AmqpClient;
+use crate::blob::BlobStore;
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::message::Message;
+use crate::models::DatabaseError;
+use crate::schema::flagged_message as flagged_message_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use crate::Context;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::prelude::*;
+use diesel::{Insertable, PgConnection, Queryable};
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// A message that a moderation filter flagged, waiting for a moderator to review it
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = flagged_message_schema)]
+pub struct FlaggedMessage {
+    id: Vec<u8>,
+    message_id: Vec<u8>,
+    timestamp: OffsetDateTime,
+    reasons: Vec<String>,
+}
+
+impl FlaggedMessage {
+    /// Add a message to the review queue. Should be run in the transaction that stores the
+    /// message.
+    pub(crate) fn flag(
+        client: &mut PgConnection,
+        message_id: &[u8],
+        reasons: Vec<String>,
+    ) -> QueryResult<()> {
+        let timestamp = time_in_millis();
+        diesel::insert_into(flagged_message_schema::table)
+            .values(FlaggedMessage {
+                id: snowflake(timestamp),
+                message_id: message_id.to_vec(),
+                timestamp,
+                reasons,
+            })
+            .execute(client)?;
+        Ok(())
+    }
+
+    /// The flag as it is recorded in the audit log
+    fn to_json(&self) -> Value {
+        json!({
+            "id": BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            "messageId": BASE64_URL_SAFE_NO_PAD.encode(&self.message_id),
+            "timestamp": self.timestamp.format(&Rfc3339).ok(),
+            "reasons": self.reasons,
+        })
+    }
+
+    /// The message that was flagged
+    pub fn message_snowflake_id(&self) -> &[u8] {
+        &self.message_id
+    }
+
+    pub async fn find(pool: &Pool, id: Vec<u8>) -> Result<Option<FlaggedMessage>, DatabaseError> {
+        let client = pool.get().await?;
+        let result = client
+            .interact(|client| {
+                flagged_message_schema::table
+                    .find(id)
+                    .first::<FlaggedMessage>(client)
+                    .optional()
+            })
+            .await??;
+
+        Ok(result)
+    }
+
+    /// The review queue, oldest first
+    pub async fn list(pool: &Pool) -> Result<Vec<FlaggedMessage>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(|client| {
+                flagged_message_schema::table
+                    .order(flagged_message_schema::id.asc())
+                    .load::<FlaggedMessage>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+
+    /// Remove a message from the review queue, leaving the message itself in place, and return
+    /// whether it was in the queue
+    pub async fn dismiss(pool: &Pool, actor: &Actor, id: Vec<u8>) -> Result<bool, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let dismissed = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let dismissed = diesel::delete(flagged_message_schema::table.find(id))
+                        .get_result::<FlaggedMessage>(client)
+                        .optional()?;
+                    if let Some(flagged) = &dismissed {
+                        AuditEvent::record(
+                            client,
+                            vec![AuditEvent::new(
+                                &actor,
+                                "flagged_message.dismiss",
+                                "flagged_message",
+                                &flagged.id,
+                                Some(flagged.to_json()),
+                                None,
+                            )],
+                        )?;
+                    }
+                    QueryResult::Ok(dismissed)
+                })
+            })
+            .await??;
+
+        Ok(dismissed.is_some())
+    }
+
+    /// Delete a flagged message, which removes it from the review queue, and return whether it
+    /// still existed
+    pub async fn remove(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        blob_store: &dyn BlobStore,
+        actor: &Actor,
+        id: Vec<u8>,
+    ) -> Result<bool, DatabaseError> {
+        match FlaggedMessage::find(pool, id).await? {
+            Some(flagged) => {
+                Message::delete(pool, amqp_client, blob_store, actor, flagged.message_id).await
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The ID of the flagged message
+    pub fn message_id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.message_id)
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A message that a moderation filter flagged, waiting for a moderator to review it
+impl FlaggedMessage {
+    /// The flag's unique ID
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// The flagged message
+    pub async fn message(&self, context: &Context) -> Option<Message> {
+        context.message_loader.load(self.message_id.clone()).await
+    }
+
+    /// The names of the filters that flagged the message
+    pub fn reasons(&self) -> Vec<String> {
+        self.reasons.clone()
+    }
+
+    /// The time the message was flagged
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+}
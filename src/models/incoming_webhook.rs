@@ -0,0 +1,193 @@
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::DatabaseError;
+use crate::schema::incoming_webhook as incoming_webhook_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::prelude::*;
+use diesel::{Insertable, Queryable};
+use rand::random;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = incoming_webhook_schema)]
+pub struct IncomingWebhook {
+    id: Vec<u8>,
+    timestamp: OffsetDateTime,
+    name: String,
+    /// Only the token's hash is stored, so that a copy of the database cannot post messages
+    token_hash: String,
+}
+
+/// An incoming webhook that was just created, with its token. This is the only time its URL is
+/// shown.
+pub struct CreatedIncomingWebhook(IncomingWebhook, String);
+
+/// The hex-encoded SHA-256 hash of a token, as it is stored
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl IncomingWebhook {
+    /// The webhook as it is recorded in the audit log, without its token
+    fn to_json(&self) -> Value {
+        json!({
+            "id": BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            "timestamp": self.timestamp.format(&Rfc3339).ok(),
+            "name": self.name,
+        })
+    }
+
+    /// Create an incoming webhook with a random 256-bit token
+    pub async fn create(
+        pool: &Pool,
+        actor: &Actor,
+        name: String,
+    ) -> Result<CreatedIncomingWebhook, DatabaseError> {
+        let timestamp = time_in_millis();
+        let token = BASE64_URL_SAFE_NO_PAD.encode(random::<[u8; 32]>());
+        let webhook = IncomingWebhook {
+            id: snowflake(timestamp),
+            timestamp,
+            name,
+            token_hash: hash_token(&token),
+        };
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let webhook = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let webhook = diesel::insert_into(incoming_webhook_schema::table)
+                        .values(webhook)
+                        .get_result::<IncomingWebhook>(client)?;
+                    AuditEvent::record(
+                        client,
+                        vec![AuditEvent::new(
+                            &actor,
+                            "incoming_webhook.create",
+                            "incoming_webhook",
+                            &webhook.id,
+                            None,
+                            Some(webhook.to_json()),
+                        )],
+                    )?;
+                    QueryResult::Ok(webhook)
+                })
+            })
+            .await??;
+
+        Ok(CreatedIncomingWebhook(webhook, token))
+    }
+
+    /// Delete an incoming webhook, revoking its token, and return whether it existed
+    pub async fn delete(pool: &Pool, actor: &Actor, id: Vec<u8>) -> Result<bool, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let deleted = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let deleted = diesel::delete(incoming_webhook_schema::table.find(id))
+                        .get_result::<IncomingWebhook>(client)
+                        .optional()?;
+                    if let Some(webhook) = &deleted {
+                        AuditEvent::record(
+                            client,
+                            vec![AuditEvent::new(
+                                &actor,
+                                "incoming_webhook.delete",
+                                "incoming_webhook",
+                                &webhook.id,
+                                Some(webhook.to_json()),
+                                None,
+                            )],
+                        )?;
+                    }
+                    QueryResult::Ok(deleted)
+                })
+            })
+            .await??;
+
+        Ok(deleted.is_some())
+    }
+
+    pub async fn list(pool: &Pool) -> Result<Vec<IncomingWebhook>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(|client| {
+                incoming_webhook_schema::table
+                    .order(incoming_webhook_schema::id.asc())
+                    .load::<IncomingWebhook>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+
+    /// The incoming webhook with the given token, looked up by its hash
+    pub async fn find_by_token(
+        pool: &Pool,
+        token: String,
+    ) -> Result<Option<IncomingWebhook>, DatabaseError> {
+        let token_hash = hash_token(&token);
+        let client = pool.get().await?;
+        let result = client
+            .interact(|client| {
+                incoming_webhook_schema::table
+                    .filter(incoming_webhook_schema::token_hash.eq(token_hash))
+                    .first::<IncomingWebhook>(client)
+                    .optional()
+            })
+            .await??;
+
+        Ok(result)
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A URL that external tools can POST to in order to send messages. The URL is a secret, so it
+/// is only shown when the incoming webhook is created.
+impl IncomingWebhook {
+    /// The incoming webhook's unique ID
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// A name describing what posts to the webhook
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// The time the incoming webhook was created
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// An incoming webhook that was just created, with the URL to POST messages to
+impl CreatedIncomingWebhook {
+    /// The incoming webhook's unique ID
+    pub fn id(&self) -> String {
+        self.0.id()
+    }
+
+    /// A name describing what posts to the webhook
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+
+    /// The path to POST messages to. Anyone with this path can send messages, and it is not
+    /// shown again, so store it somewhere safe.
+    pub fn url(&self) -> String {
+        format!("/api/v1/hooks/{}", self.1)
+    }
+
+    /// The time the incoming webhook was created
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.0.timestamp()
+    }
+}
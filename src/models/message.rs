@@ -1,5 +1,14 @@
-use crate::amqp::{AmqpError, Protobuf};
-use crate::protos::Message as MessageProto;
+use crate::amqp::{AmqpClient, AmqpError, Exchange, Protobuf};
+use crate::blob::BlobStore;
+use crate::markdown::render;
+use crate::models::attachment::Attachment;
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::flagged_message::FlaggedMessage;
+use crate::models::message_change::{ChangeKind, MessageChange};
+use crate::models::report::{Report, ReportStatus};
+use crate::models::DatabaseError;
+use crate::moderation::Moderated;
+use crate::protos::{DeletedMessage as DeletedMessageProto, Message as MessageProto};
 use crate::schema::message as message_schema;
 use crate::snowflake::{snowflake, time_in_millis};
 use base64::prelude::BASE64_URL_SAFE_NO_PAD;
@@ -7,21 +16,119 @@ use base64::Engine;
 use dataloader::cached::Loader;
 use dataloader::BatchFn;
 use deadpool_diesel::postgres::Pool;
+use diesel::deserialize;
+use diesel::pg::Pg;
 use diesel::prelude::*;
-use diesel::{Insertable, Queryable};
+use diesel::{PgConnection, Queryable};
 use juniper::async_trait;
 use protobuf::Message as ProtobufMessage;
 use protobuf::SpecialFields;
+use serde::ser::{Error as _, SerializeStruct};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::error::Error;
-use time::OffsetDateTime;
+use std::fmt::{Display, Formatter};
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
 
-#[derive(Queryable, Insertable, Clone)]
-#[diesel(table_name = message_schema)]
+/// How many expired messages are deleted per transaction, or republished per query
+const BATCH_SIZE: i64 = 100;
+
+/// Why a message with attachments could not be sent
+pub const INVALID_ATTACHMENTS: &str = "Attachments must exist and not belong to another message";
+
+/// Why republishing messages stopped
+#[derive(Debug)]
+pub enum RepublishError {
+    Database(DatabaseError),
+    Amqp(AmqpError),
+}
+
+impl Display for RepublishError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepublishError::Database(e) => write!(f, "Database error: {}", e),
+            RepublishError::Amqp(e) => write!(f, "Publish error: {}", e),
+        }
+    }
+}
+
+impl From<DatabaseError> for RepublishError {
+    fn from(e: DatabaseError) -> Self {
+        RepublishError::Database(e)
+    }
+}
+
+impl From<AmqpError> for RepublishError {
+    fn from(e: AmqpError) -> Self {
+        RepublishError::Amqp(e)
+    }
+}
+
+#[derive(Clone)]
 pub struct Message {
     id: Vec<u8>,
     timestamp: OffsetDateTime,
     body: String,
+    body_html: String,
+    expires_at: Option<OffsetDateTime>,
+    client_message_id: Option<String>,
+    attachments: Vec<Attachment>,
+}
+
+/// Messages removed by [`Message::delete_rows`], to be announced with [`Message::deleted`]
+/// once the transaction commits
+#[derive(Default)]
+pub(crate) struct Deletion {
+    ids: Vec<Vec<u8>>,
+    attachments: Vec<Attachment>,
+    reports: Vec<Report>,
+}
+
+impl Deletion {
+    /// How many messages were deleted
+    pub(crate) fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Whether no messages were deleted
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The reports that were resolved because their messages were deleted
+    pub(crate) fn reports(&self) -> &[Report] {
+        &self.reports
+    }
+}
+
+/// Attachments are stored in their own table, and are loaded with
+/// [`Message::with_attachments`] after the message row. Messages stored before bodies were
+/// rendered have their HTML rendered when they are loaded.
+impl Queryable<message_schema::SqlType, Pg> for Message {
+    type Row = (
+        Vec<u8>,
+        OffsetDateTime,
+        String,
+        Option<String>,
+        Option<OffsetDateTime>,
+        Option<String>,
+    );
+
+    fn build(
+        (id, timestamp, body, body_html, expires_at, client_message_id): Self::Row,
+    ) -> deserialize::Result<Self> {
+        Ok(Self {
+            id,
+            timestamp,
+            body_html: body_html.unwrap_or_else(|| render(&body)),
+            body,
+            expires_at,
+            client_message_id,
+            attachments: Vec::new(),
+        })
+    }
 }
 
 impl Message {
@@ -31,17 +138,511 @@ impl Message {
         Self {
             id,
             timestamp,
+            body_html: render(&body),
             body,
+            expires_at: None,
+            client_message_id: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    /// A new message that is deleted once `expires_in` has passed
+    pub fn expiring(body: String, expires_in: Duration) -> Self {
+        let message = Message::new(body);
+        Self {
+            expires_at: Some(message.timestamp + expires_in),
+            ..message
+        }
+    }
+
+    /// Load the attachments of each message
+    pub(crate) fn with_attachments(
+        client: &mut PgConnection,
+        mut messages: Vec<Message>,
+    ) -> QueryResult<Vec<Message>> {
+        let ids = messages.iter().map(|message| message.id.clone()).collect();
+        let mut attachments = Attachment::for_messages(client, ids)?;
+        for message in &mut messages {
+            message.attachments = attachments.remove(&message.id).unwrap_or_default();
+        }
+        Ok(messages)
+    }
+
+    /// Messages that have not expired, newest first, optionally only those older than `before`
+    pub async fn list(
+        pool: &Pool,
+        before: Option<Vec<u8>>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Message>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(move |client| {
+                let mut query = message_schema::table
+                    .filter(
+                        message_schema::expires_at
+                            .is_null()
+                            .or(message_schema::expires_at.gt(OffsetDateTime::now_utc())),
+                    )
+                    .order(message_schema::id.desc())
+                    .into_boxed();
+                if let Some(before) = before {
+                    query = query.filter(message_schema::id.lt(before));
+                }
+                if let Some(limit) = limit {
+                    query = query.limit(limit);
+                }
+                let messages = query.load::<Message>(client)?;
+                Message::with_attachments(client, messages)
+            })
+            .await??;
+
+        Ok(results)
+    }
+
+    /// Publish the unexpired messages sent in `[since, until)` again, oldest first, as if they
+    /// had just been sent, and return how many were published. Subscribers and webhooks receive
+    /// them a second time, so this is for recovering consumers that missed them.
+    ///
+    /// The request is recorded in the audit log before anything is published.
+    pub async fn republish(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        actor: &Actor,
+        since: OffsetDateTime,
+        until: OffsetDateTime,
+    ) -> Result<usize, RepublishError> {
+        Message::record_republish(pool, actor, since, until).await?;
+
+        let mut after = Vec::new();
+        let mut published = 0;
+        loop {
+            let messages = Message::republish_batch(pool, after, since, until).await?;
+            let last = match messages.last() {
+                Some(last) => last.id.clone(),
+                None => return Ok(published),
+            };
+            for message in messages {
+                amqp_client
+                    .produce(message, Exchange::Messages, "message")
+                    .await?;
+                published += 1;
+            }
+            after = last;
+        }
+    }
+
+    /// Record a request to republish messages in the audit log
+    async fn record_republish(
+        pool: &Pool,
+        actor: &Actor,
+        since: OffsetDateTime,
+        until: OffsetDateTime,
+    ) -> Result<(), DatabaseError> {
+        let event = AuditEvent::new(
+            actor,
+            "message.republish",
+            "message",
+            &[],
+            None,
+            Some(json!({
+                "since": since.format(&Rfc3339).ok(),
+                "until": until.format(&Rfc3339).ok(),
+            })),
+        );
+        let client = pool.get().await?;
+        client
+            .interact(move |client| AuditEvent::record(client, vec![event]))
+            .await??;
+        Ok(())
+    }
+
+    /// The next batch of messages to republish, after the message with ID `after`
+    async fn republish_batch(
+        pool: &Pool,
+        after: Vec<u8>,
+        since: OffsetDateTime,
+        until: OffsetDateTime,
+    ) -> Result<Vec<Message>, DatabaseError> {
+        let client = pool.get().await?;
+        let messages = client
+            .interact(move |client| {
+                let messages = message_schema::table
+                    .filter(message_schema::id.gt(after))
+                    .filter(message_schema::timestamp.ge(since))
+                    .filter(message_schema::timestamp.lt(until))
+                    .filter(
+                        message_schema::expires_at
+                            .is_null()
+                            .or(message_schema::expires_at.gt(OffsetDateTime::now_utc())),
+                    )
+                    .order(message_schema::id.asc())
+                    .limit(BATCH_SIZE)
+                    .load::<Message>(client)?;
+                Message::with_attachments(client, messages)
+            })
+            .await??;
+
+        Ok(messages)
+    }
+
+    /// The messages with the given IDs that still exist, with their attachments
+    pub(crate) fn find_all(
+        client: &mut PgConnection,
+        ids: Vec<Vec<u8>>,
+    ) -> QueryResult<Vec<Message>> {
+        let messages = message_schema::table
+            .filter(message_schema::id.eq_any(ids))
+            .load::<Message>(client)?;
+        Message::with_attachments(client, messages)
+    }
+
+    /// The unexpired message sent with the given client message ID, if there is one
+    fn find_by_client_message_id(
+        client: &mut PgConnection,
+        client_message_id: String,
+    ) -> QueryResult<Option<Message>> {
+        let message = message_schema::table
+            .filter(message_schema::client_message_id.eq(client_message_id))
+            .filter(
+                message_schema::expires_at
+                    .is_null()
+                    .or(message_schema::expires_at.gt(OffsetDateTime::now_utc())),
+            )
+            .first::<Message>(client)
+            .optional()?;
+        match message {
+            Some(message) => Ok(Message::with_attachments(client, vec![message])?.pop()),
+            None => Ok(None),
+        }
+    }
+
+    /// Take a client message ID from an expired message that has not been deleted yet, so that
+    /// it can be used again as it could once the message is deleted. Should be run in a
+    /// transaction before the ID is reused.
+    fn release_client_message_id(
+        client: &mut PgConnection,
+        client_message_id: &str,
+    ) -> QueryResult<()> {
+        diesel::update(
+            message_schema::table
+                .filter(message_schema::client_message_id.eq(client_message_id))
+                .filter(message_schema::expires_at.le(OffsetDateTime::now_utc())),
+        )
+        .set(message_schema::client_message_id.eq(None::<String>))
+        .execute(client)?;
+        Ok(())
+    }
+
+    /// Insert a message and add the given uploaded attachments to it. Should be run in a
+    /// transaction, so that the message is not stored if an attachment cannot be added.
+    pub(crate) fn insert(
+        client: &mut PgConnection,
+        message: Message,
+        attachments: &[Vec<u8>],
+    ) -> Result<Message, DatabaseError> {
+        diesel::insert_into(message_schema::table)
+            .values((
+                message_schema::id.eq(&message.id),
+                message_schema::timestamp.eq(message.timestamp),
+                message_schema::body.eq(&message.body),
+                message_schema::body_html.eq(&message.body_html),
+                message_schema::expires_at.eq(message.expires_at),
+                message_schema::client_message_id.eq(&message.client_message_id),
+            ))
+            .execute(client)?;
+        if Attachment::link(client, &message.id, attachments)? != attachments.len() {
+            return Err(DatabaseError {
+                message: INVALID_ATTACHMENTS.to_string(),
+            });
+        }
+        MessageChange::record(client, ChangeKind::Created, &[message.id.clone()])?;
+        let mut messages = Message::with_attachments(client, vec![message])?;
+        Ok(messages.remove(0))
+    }
+
+    /// Store a new message with the given uploaded attachments and publish it to subscribers.
+    /// If `expires_in` is given, the message is deleted once it has passed. If moderation
+    /// flagged the body, the message is added to the review queue. Returns `None` if an
+    /// attachment does not exist or belongs to another message.
+    ///
+    /// If a message was already sent with the same `client_message_id`, that message is
+    /// returned instead and nothing is published, so clients can safely retry. Once that
+    /// message expires, the ID can be used again. The ID is unique across all messages, not per
+    /// sender, since there are no users to scope it to, so clients should generate random IDs
+    /// such as UUIDs. Concurrent retries cannot both store a message.
+    pub async fn send(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        body: Moderated,
+        mut attachments: Vec<Vec<u8>>,
+        expires_in: Option<Duration>,
+        client_message_id: Option<String>,
+    ) -> Result<Option<Message>, DatabaseError> {
+        let (body, flags) = body.into_parts();
+        let message = match expires_in {
+            Some(expires_in) => Message::expiring(body, expires_in),
+            None => Message::new(body),
+        };
+        let message = Message {
+            client_message_id: client_message_id.clone(),
+            ..message
+        };
+        attachments.sort();
+        attachments.dedup();
+
+        let client = pool.get().await?;
+        let result: Result<Option<(Message, bool)>, DatabaseError> = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    if let Some(client_message_id) = message.client_message_id.clone() {
+                        if let Some(sent) =
+                            Message::find_by_client_message_id(client, client_message_id.clone())?
+                        {
+                            return Ok(Some((sent, false)));
+                        }
+                        Message::release_client_message_id(client, &client_message_id)?;
+                    }
+                    if !Attachment::lock_unused(client, &attachments)? {
+                        return Ok(None);
+                    }
+                    let message = Message::insert(client, message, &attachments)?;
+                    if !flags.is_empty() {
+                        FlaggedMessage::flag(client, &message.id, flags)?;
+                    }
+                    Ok(Some((message, true)))
+                })
+            })
+            .await?;
+
+        let message = match (result, client_message_id) {
+            (Ok(Some((message, true))), _) => message,
+            (Ok(Some((message, false))), _) => return Ok(Some(message)),
+            (Ok(None), _) => return Ok(None),
+            // A concurrent retry stored the message first
+            (Err(err), Some(client_message_id)) => {
+                let sent = client
+                    .interact(|client| {
+                        Message::find_by_client_message_id(client, client_message_id)
+                    })
+                    .await??;
+                return sent.map(Some).ok_or(err);
+            }
+            (Err(err), None) => return Err(err),
+        };
+
+        amqp_client.produce_in_background(message.clone(), Exchange::Messages, "message");
+
+        Ok(Some(message))
+    }
+
+    /// Delete messages, record the deletions in the change log, and resolve the messages' open
+    /// reports. Should be run in a transaction.
+    pub(crate) fn delete_rows(
+        client: &mut PgConnection,
+        actor: &Actor,
+        ids: Vec<Vec<u8>>,
+    ) -> QueryResult<Deletion> {
+        let mut attachments = Attachment::for_messages(client, ids.clone())?;
+        // Reports are resolved even if their message is already gone, so none stay open
+        let reports = Report::resolve_rows(
+            client,
+            actor,
+            ReportStatus::Deleted,
+            None,
+            Some(ids.clone()),
+        )?;
+        let ids = diesel::delete(message_schema::table.filter(message_schema::id.eq_any(ids)))
+            .returning(message_schema::id)
+            .get_results::<Vec<u8>>(client)?;
+        MessageChange::record(client, ChangeKind::Deleted, &ids)?;
+        let attachments = ids
+            .iter()
+            .filter_map(|id| attachments.remove(id))
+            .flatten()
+            .collect();
+        Ok(Deletion {
+            ids,
+            attachments,
+            reports,
+        })
+    }
+
+    /// Once deletions are committed, remove the attachments' contents and publish the
+    /// deletions to subscribers and the resolved reports to moderators
+    pub(crate) async fn deleted(
+        amqp_client: &AmqpClient,
+        blob_store: &dyn BlobStore,
+        deletion: Deletion,
+    ) {
+        for attachment in deletion.attachments {
+            if let Err(err) = blob_store.delete(&attachment.key()).await {
+                println!("Error deleting attachment {}: {}", attachment.key(), err);
+            }
         }
+        for id in deletion.ids {
+            if let Err(err) = amqp_client
+                .produce(DeletedMessage { id }, Exchange::Messages, "message.deleted")
+                .await
+            {
+                println!("Error publishing message deletion: {}", err);
+            }
+        }
+        for report in deletion.reports {
+            report.publish(amqp_client);
+        }
+    }
+
+    /// Delete a message and record the deletion in the audit log. Should be run in a
+    /// transaction.
+    pub(crate) fn delete_audited(
+        client: &mut PgConnection,
+        actor: &Actor,
+        id: Vec<u8>,
+    ) -> QueryResult<Deletion> {
+        let messages = message_schema::table
+            .find(&id)
+            .for_update()
+            .load::<Message>(client)?;
+        let messages = Message::with_attachments(client, messages)?;
+        let deletion = Message::delete_rows(client, actor, vec![id])?;
+        let events = messages
+            .iter()
+            .map(|message| {
+                AuditEvent::new(
+                    actor,
+                    "message.delete",
+                    "message",
+                    &message.id,
+                    Some(message.to_json()),
+                    None,
+                )
+            })
+            .collect();
+        AuditEvent::record(client, events)?;
+        Ok(deletion)
+    }
+
+    /// Delete a message along with its attachments, publish the deletion to subscribers, and
+    /// return whether it existed
+    pub async fn delete(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        blob_store: &dyn BlobStore,
+        actor: &Actor,
+        id: Vec<u8>,
+    ) -> Result<bool, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let deletion = client
+            .interact(move |client| {
+                client.transaction(|client| Message::delete_audited(client, &actor, id))
+            })
+            .await??;
+
+        let deleted = !deletion.is_empty();
+        Message::deleted(amqp_client, blob_store, deletion).await;
+        Ok(deleted)
+    }
+
+    /// Delete messages that have expired, along with their attachments, and publish the
+    /// deletions to subscribers. Returns how many messages were deleted. Expiry is not a
+    /// privileged action, so it is left out of the audit log; the change log records it.
+    ///
+    /// Expired rows are locked with `FOR UPDATE SKIP LOCKED`, so that when several instances
+    /// run this at once each deletion is published only once.
+    pub async fn delete_expired(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        blob_store: &dyn BlobStore,
+    ) -> Result<usize, DatabaseError> {
+        let client = pool.get().await?;
+        let deletion = client
+            .interact(|client| {
+                client.transaction(|client| {
+                    let ids = message_schema::table
+                        .select(message_schema::id)
+                        .filter(message_schema::expires_at.le(OffsetDateTime::now_utc()))
+                        .limit(BATCH_SIZE)
+                        .for_update()
+                        .skip_locked()
+                        .load::<Vec<u8>>(client)?;
+                    Message::delete_rows(client, &Actor::system(), ids)
+                })
+            })
+            .await??;
+
+        let deleted = deletion.len();
+        Message::deleted(amqp_client, blob_store, deletion).await;
+        Ok(deleted)
+    }
+}
+
+impl Message {
+    /// The raw snowflake ID, which sorts in the order messages were sent
+    pub fn snowflake_id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// The message as it is recorded in the audit log. The body is left out, because the log
+    /// is never purged and would otherwise keep deleted messages forever.
+    fn to_json(&self) -> Value {
+        json!({
+            "id": BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            "timestamp": self.timestamp.format(&Rfc3339).ok(),
+            "expiresAt": self
+                .expires_at
+                .and_then(|expires_at| expires_at.format(&Rfc3339).ok()),
+            "clientMessageId": self.client_message_id,
+            "attachments": self
+                .attachments
+                .iter()
+                .map(|attachment| attachment.key())
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Message", 7)?;
+        state.serialize_field("id", &self.id())?;
+        state.serialize_field("body", &self.body)?;
+        state.serialize_field("bodyHtml", &self.body_html)?;
+        state.serialize_field("attachments", &self.attachments)?;
+        state.serialize_field(
+            "timestamp",
+            &self.timestamp.format(&Rfc3339).map_err(S::Error::custom)?,
+        )?;
+        state.serialize_field(
+            "expiresAt",
+            &self
+                .expires_at
+                .map(|expires_at| expires_at.format(&Rfc3339))
+                .transpose()
+                .map_err(S::Error::custom)?,
+        )?;
+        state.serialize_field("clientMessageId", &self.client_message_id)?;
+        state.end()
     }
 }
 
 impl Protobuf for Message {
     fn try_to_protobuf(self) -> Result<Vec<u8>, AmqpError> {
         let message = MessageProto {
+            attachments: self
+                .attachments
+                .iter()
+                .map(Attachment::to_protobuf)
+                .collect(),
             id: self.id,
             timestamp: (self.timestamp.unix_timestamp_nanos() / 1_000_000) as u64,
             body: self.body,
+            body_html: self.body_html,
+            expires_at: self
+                .expires_at
+                .map(|expires_at| (expires_at.unix_timestamp_nanos() / 1_000_000) as u64)
+                .unwrap_or_default(),
+            client_message_id: self.client_message_id.unwrap_or_default(),
             special_fields: SpecialFields::new(),
         };
         match message.write_to_bytes() {
@@ -53,7 +654,7 @@ impl Protobuf for Message {
     }
 
     fn try_from_protobuf(payload: &[u8]) -> Result<Self, AmqpError> {
-        let message = crate::protos::Message::parse_from_bytes(payload).unwrap();
+        let message = crate::protos::Message::parse_from_bytes(payload)?;
         Ok(Self {
             id: message.id.to_vec(),
             timestamp: match OffsetDateTime::from_unix_timestamp_nanos(
@@ -66,7 +667,24 @@ impl Protobuf for Message {
                     })
                 }
             },
+            body_html: match message.body_html.is_empty() {
+                true => render(&message.body),
+                false => message.body_html.to_string(),
+            },
             body: message.body.to_string(),
+            expires_at: match message.expires_at {
+                0 => None,
+                expires_at => Some(OffsetDateTime::from_unix_timestamp_nanos(
+                    expires_at as i128 * 1_000_000,
+                )?),
+            },
+            client_message_id: Some(message.client_message_id.to_string())
+                .filter(|client_message_id| !client_message_id.is_empty()),
+            attachments: message
+                .attachments
+                .iter()
+                .map(|attachment| Attachment::from_protobuf(attachment, &message.id))
+                .collect::<Result<_, _>>()?,
         })
     }
 }
@@ -79,15 +697,74 @@ impl Message {
         BASE64_URL_SAFE_NO_PAD.encode(&self.id)
     }
 
-    /// The text of the message
+    /// The text of the message, as entered
     pub fn body(&self) -> String {
         self.body.clone()
     }
 
+    /// The text of the message rendered from Markdown to sanitized HTML
+    pub fn body_html(&self) -> String {
+        self.body_html.clone()
+    }
+
     /// The time the message was sent
     pub fn timestamp(&self) -> OffsetDateTime {
         self.timestamp
     }
+
+    /// The files attached to the message
+    pub fn attachments(&self) -> Vec<Attachment> {
+        self.attachments.clone()
+    }
+
+    /// The time the message will be deleted, if it expires
+    pub fn expires_at(&self) -> Option<OffsetDateTime> {
+        self.expires_at
+    }
+
+    /// The ID the sending client gave the message, if any, so that it can match the message
+    /// to one it is displaying optimistically
+    pub fn client_message_id(&self) -> Option<String> {
+        self.client_message_id.clone()
+    }
+}
+
+/// Published when a message is deleted, so that subscribers can remove it from view
+#[derive(Clone)]
+pub struct DeletedMessage {
+    id: Vec<u8>,
+}
+
+impl Serialize for DeletedMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("DeletedMessage", 1)?;
+        state.serialize_field("id", &self.id())?;
+        state.end()
+    }
+}
+
+impl Protobuf for DeletedMessage {
+    fn try_to_protobuf(self) -> Result<Vec<u8>, AmqpError> {
+        let message = DeletedMessageProto {
+            id: self.id,
+            special_fields: SpecialFields::new(),
+        };
+        Ok(message.write_to_bytes()?)
+    }
+
+    fn try_from_protobuf(payload: &[u8]) -> Result<Self, AmqpError> {
+        let message = DeletedMessageProto::parse_from_bytes(payload)?;
+        Ok(Self { id: message.id })
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A message that was deleted
+impl DeletedMessage {
+    /// The ID of the deleted message
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
 }
 
 pub struct MessageBatcher {
@@ -109,9 +786,15 @@ impl BatchFn<Vec<u8>, Option<Message>> for MessageBatcher {
 
             let results: Vec<Message> = client
                 .interact(|client| {
-                    message_schema::table
+                    let messages = message_schema::table
                         .filter(message_schema::id.eq_any(new_keys))
-                        .load::<Message>(client)
+                        .filter(
+                            message_schema::expires_at
+                                .is_null()
+                                .or(message_schema::expires_at.gt(OffsetDateTime::now_utc())),
+                        )
+                        .load::<Message>(client)?;
+                    Message::with_attachments(client, messages)
                 })
                 .await??;
 
@@ -152,7 +835,18 @@ mod tests {
             id,
             timestamp,
             body: "Hello, world!".to_string(),
+            body_html: "<p>Hello, world!</p>\n".to_string(),
+            expires_at: None,
+            client_message_id: None,
+            attachments: Vec::new(),
         };
         assert_eq!(&message.id()[..7], "Dcas-sA");
     }
+
+    #[test]
+    #[parallel]
+    fn test_malformed_protobuf() {
+        // A truncated varint, which consumers must reject rather than panic on
+        assert!(Message::try_from_protobuf(&[0xff, 0xff, 0xff]).is_err());
+    }
 }
@@ -0,0 +1,395 @@
+use crate::models::message::Message;
+use crate::models::{ago, DatabaseError};
+use crate::schema::message as message_schema;
+use crate::schema::message_change as message_change_schema;
+use crate::schema::message_change_compaction as compaction_schema;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::deserialize;
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use diesel::{PgConnection, Queryable};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// The oldest transaction that was still running when the current snapshot was taken. Every
+/// change made by an older transaction is either visible or was rolled back.
+const SNAPSHOT_XMIN: &str = "pg_snapshot_xmin(pg_current_snapshot())::text::bigint";
+
+#[derive(Clone, Copy, PartialEq, Eq, juniper::GraphQLEnum)]
+/// What happened to a message
+pub enum ChangeKind {
+    /// The message was sent
+    Created,
+    /// The message was deleted, such as when it expired or was archived
+    Deleted,
+}
+
+impl ChangeKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// A position in the change log: the transaction that made a change, then the change's sequence
+/// number within the log
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SyncCursor {
+    txid: i64,
+    seq: i64,
+}
+
+impl SyncCursor {
+    /// Parse a cursor returned by a previous sync
+    pub fn parse(cursor: &str) -> Option<Self> {
+        let (txid, seq) = cursor.split_once('.')?;
+        Some(Self {
+            txid: txid.parse().ok()?,
+            seq: seq.parse().ok()?,
+        })
+    }
+}
+
+impl Display for SyncCursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.txid, self.seq)
+    }
+}
+
+/// An entry in the change log, which records every change to the message table
+#[derive(Clone)]
+pub struct MessageChange {
+    seq: i64,
+    txid: i64,
+    message_id: Vec<u8>,
+    kind: ChangeKind,
+    timestamp: OffsetDateTime,
+    message: Option<Message>,
+}
+
+impl Queryable<message_change_schema::SqlType, Pg> for MessageChange {
+    type Row = (i64, i64, Vec<u8>, String, OffsetDateTime);
+
+    fn build((seq, txid, message_id, kind, timestamp): Self::Row) -> deserialize::Result<Self> {
+        let kind = match kind.as_str() {
+            "created" => ChangeKind::Created,
+            "deleted" => ChangeKind::Deleted,
+            kind => return Err(format!("Unknown change kind: {}", kind).into()),
+        };
+        Ok(Self {
+            seq,
+            txid,
+            message_id,
+            kind,
+            timestamp,
+            message: None,
+        })
+    }
+}
+
+/// The changes since a client's cursor
+pub struct SyncResult {
+    changes: Vec<MessageChange>,
+    cursor: SyncCursor,
+    resync: bool,
+    has_more: bool,
+}
+
+impl SyncResult {
+    /// The position to sync from next
+    pub(crate) fn next_cursor(&self) -> SyncCursor {
+        self.cursor
+    }
+}
+
+impl MessageChange {
+    /// The change's position in the log
+    pub(crate) fn cursor(&self) -> SyncCursor {
+        SyncCursor {
+            txid: self.txid,
+            seq: self.seq,
+        }
+    }
+
+    /// Append a change for each message to the log. Must be run in the transaction that makes
+    /// the changes.
+    ///
+    /// Sequence numbers are not committed in order, so each change also records its
+    /// transaction's ID, and syncs only return changes whose transaction has ended.
+    pub(crate) fn record(
+        client: &mut PgConnection,
+        kind: ChangeKind,
+        message_ids: &[Vec<u8>],
+    ) -> QueryResult<()> {
+        if message_ids.is_empty() {
+            return Ok(());
+        }
+        let timestamp = OffsetDateTime::now_utc();
+        let rows: Vec<_> = message_ids
+            .iter()
+            .map(|message_id| {
+                (
+                    message_change_schema::message_id.eq(message_id.clone()),
+                    message_change_schema::kind.eq(kind.as_str()),
+                    message_change_schema::timestamp.eq(timestamp),
+                )
+            })
+            .collect();
+        diesel::insert_into(message_change_schema::table)
+            .values(rows)
+            .execute(client)?;
+        Ok(())
+    }
+
+    /// Up to `limit` changes after the cursor `since`, oldest first, with the current state of
+    /// each created message.
+    ///
+    /// If `since` is not given, or the changes after it have been compacted away, no changes are
+    /// returned and `resync` is set. The client should then take the returned cursor, refetch
+    /// all messages, and sync from that cursor.
+    ///
+    /// Changes are ordered by transaction ID, and only changes made by transactions older than
+    /// every running transaction are returned, so a cursor never moves past a change that could
+    /// still be committed. A long-running transaction holds back changes made after it started
+    /// until it ends.
+    pub async fn since(
+        pool: &Pool,
+        since: Option<SyncCursor>,
+        limit: i64,
+    ) -> Result<SyncResult, DatabaseError> {
+        let client = pool.get().await?;
+        let result = client
+            .interact(move |client| {
+                client
+                    .build_transaction()
+                    .repeatable_read()
+                    .read_only()
+                    .run(|client| {
+                        let xmin = diesel::select(sql::<BigInt>(SNAPSHOT_XMIN))
+                            .get_result::<i64>(client)?;
+                        let (txid, seq) = compaction_schema::table
+                            .select((compaction_schema::through_txid, compaction_schema::through))
+                            .first::<(i64, i64)>(client)?;
+                        let compacted_through = SyncCursor { txid, seq };
+                        let since = match since {
+                            Some(since) if since >= compacted_through => since,
+                            _ => {
+                                let latest = message_change_schema::table
+                                    .filter(message_change_schema::txid.lt(xmin))
+                                    .order((
+                                        message_change_schema::txid.desc(),
+                                        message_change_schema::seq.desc(),
+                                    ))
+                                    .first::<MessageChange>(client)
+                                    .optional()?;
+                                return Ok(SyncResult {
+                                    changes: Vec::new(),
+                                    cursor: latest
+                                        .map(|change| change.cursor())
+                                        .unwrap_or_default()
+                                        .max(compacted_through),
+                                    resync: true,
+                                    has_more: false,
+                                });
+                            }
+                        };
+
+                        let mut changes = message_change_schema::table
+                            .filter(message_change_schema::txid.lt(xmin))
+                            .filter(
+                                message_change_schema::txid.gt(since.txid).or(
+                                    message_change_schema::txid
+                                        .eq(since.txid)
+                                        .and(message_change_schema::seq.gt(since.seq)),
+                                ),
+                            )
+                            .order((
+                                message_change_schema::txid.asc(),
+                                message_change_schema::seq.asc(),
+                            ))
+                            .limit(limit + 1)
+                            .load::<MessageChange>(client)?;
+                        let has_more = changes.len() as i64 > limit;
+                        changes.truncate(limit as usize);
+
+                        let created = changes
+                            .iter()
+                            .filter(|change| change.kind == ChangeKind::Created)
+                            .map(|change| change.message_id.clone())
+                            .collect::<Vec<_>>();
+                        let messages = message_schema::table
+                            .filter(message_schema::id.eq_any(created))
+                            .filter(
+                                message_schema::expires_at
+                                    .is_null()
+                                    .or(message_schema::expires_at.gt(OffsetDateTime::now_utc())),
+                            )
+                            .load::<Message>(client)?;
+                        let mut messages: HashMap<Vec<u8>, Message> =
+                            Message::with_attachments(client, messages)?
+                                .into_iter()
+                                .map(|message| (message.snowflake_id().to_vec(), message))
+                                .collect();
+                        for change in &mut changes {
+                            if change.kind == ChangeKind::Created {
+                                change.message = messages.remove(&change.message_id);
+                            }
+                        }
+
+                        QueryResult::Ok(SyncResult {
+                            cursor: changes
+                                .last()
+                                .map(|change| change.cursor())
+                                .unwrap_or(since),
+                            changes,
+                            resync: false,
+                            has_more,
+                        })
+                    })
+            })
+            .await??;
+
+        Ok(result)
+    }
+
+    /// Delete changes older than `max_age`. Clients whose cursor is older than the newest
+    /// deleted change are told to resync. Returns how many changes were deleted.
+    pub async fn compact(pool: &Pool, max_age: Duration) -> Result<usize, DatabaseError> {
+        // Nothing is older than the earliest time that can be represented
+        let oldest_kept = match ago(max_age) {
+            Some(oldest_kept) => oldest_kept,
+            None => return Ok(0),
+        };
+        let client = pool.get().await?;
+        let deleted = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let xmin =
+                        diesel::select(sql::<BigInt>(SNAPSHOT_XMIN)).get_result::<i64>(client)?;
+                    let newest = message_change_schema::table
+                        .filter(message_change_schema::txid.lt(xmin))
+                        .filter(message_change_schema::timestamp.lt(oldest_kept))
+                        .order((
+                            message_change_schema::txid.desc(),
+                            message_change_schema::seq.desc(),
+                        ))
+                        .first::<MessageChange>(client)
+                        .optional()?;
+                    let through = match newest {
+                        Some(newest) => newest.cursor(),
+                        None => return Ok(0),
+                    };
+
+                    diesel::update(
+                        compaction_schema::table.filter(
+                            compaction_schema::through_txid.lt(through.txid).or(
+                                compaction_schema::through_txid
+                                    .eq(through.txid)
+                                    .and(compaction_schema::through.lt(through.seq)),
+                            ),
+                        ),
+                    )
+                    .set((
+                        compaction_schema::through_txid.eq(through.txid),
+                        compaction_schema::through.eq(through.seq),
+                    ))
+                    .execute(client)?;
+                    diesel::delete(
+                        message_change_schema::table.filter(
+                            message_change_schema::txid.lt(through.txid).or(
+                                message_change_schema::txid
+                                    .eq(through.txid)
+                                    .and(message_change_schema::seq.le(through.seq)),
+                            ),
+                        ),
+                    )
+                    .execute(client)
+                })
+            })
+            .await??;
+
+        Ok(deleted)
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A change to a message
+impl MessageChange {
+    /// What happened to the message
+    pub fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+
+    /// The ID of the message that changed
+    pub fn message_id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.message_id)
+    }
+
+    /// The message as it is now, for created messages that have not since been deleted
+    pub fn message(&self) -> Option<Message> {
+        self.message.clone()
+    }
+
+    /// The time the change was made
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// The changes to messages since a client last synced
+impl SyncResult {
+    /// The changes, in the order they were made
+    pub fn changes(&self) -> Vec<MessageChange> {
+        self.changes.clone()
+    }
+
+    /// The cursor to pass as `since` on the next sync
+    pub fn cursor(&self) -> String {
+        self.cursor.to_string()
+    }
+
+    /// Whether the changes since the cursor are no longer available, so the client must refetch
+    /// all messages before syncing from `cursor`
+    pub fn resync(&self) -> bool {
+        self.resync
+    }
+
+    /// Whether there are more changes after these
+    pub fn has_more(&self) -> bool {
+        self.has_more
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::parallel;
+
+    #[test]
+    #[parallel]
+    fn test_parse_cursor() {
+        let cursor = SyncCursor { txid: 7, seq: 42 };
+        assert!(SyncCursor::parse(&cursor.to_string()) == Some(cursor));
+        assert!(SyncCursor::parse("42").is_none());
+        assert!(SyncCursor::parse("7.").is_none());
+        assert!(SyncCursor::parse("nonsense").is_none());
+    }
+
+    #[test]
+    #[parallel]
+    fn test_cursor_order() {
+        let cursor = SyncCursor { txid: 7, seq: 42 };
+        assert!(cursor < SyncCursor { txid: 8, seq: 1 });
+        assert!(cursor < SyncCursor { txid: 7, seq: 43 });
+        assert!(cursor > SyncCursor { txid: 0, seq: 100 });
+    }
+}
@@ -0,0 +1,449 @@
+use crate::amqp::{AmqpClient, AmqpError, Exchange, Protobuf};
+use crate::blob::BlobStore;
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::message::Message;
+use crate::models::DatabaseError;
+use crate::protos::Report as ReportProto;
+use crate::schema::message as message_schema;
+use crate::schema::report as report_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use crate::Context;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::deserialize;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::{PgConnection, Queryable};
+use protobuf::Message as ProtobufMessage;
+use protobuf::SpecialFields;
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, juniper::GraphQLEnum)]
+/// Where a report is in moderator review
+pub enum ReportStatus {
+    /// Waiting for a moderator
+    Open,
+    /// A moderator decided the message was acceptable
+    Dismissed,
+    /// The message was deleted, by a moderator or because it expired or was archived
+    Deleted,
+}
+
+impl ReportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Dismissed => "dismissed",
+            ReportStatus::Deleted => "deleted",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "open" => Some(ReportStatus::Open),
+            "dismissed" => Some(ReportStatus::Dismissed),
+            "deleted" => Some(ReportStatus::Deleted),
+            _ => None,
+        }
+    }
+}
+
+/// A report that a message breaks the rules
+#[derive(Clone)]
+pub struct Report {
+    id: Vec<u8>,
+    message_id: Vec<u8>,
+    timestamp: OffsetDateTime,
+    reason: String,
+    status: ReportStatus,
+    resolved_at: Option<OffsetDateTime>,
+}
+
+impl Queryable<report_schema::SqlType, Pg> for Report {
+    type Row = (
+        Vec<u8>,
+        Vec<u8>,
+        OffsetDateTime,
+        String,
+        String,
+        Option<OffsetDateTime>,
+    );
+
+    fn build(
+        (id, message_id, timestamp, reason, status, resolved_at): Self::Row,
+    ) -> deserialize::Result<Self> {
+        Ok(Self {
+            id,
+            message_id,
+            timestamp,
+            reason,
+            status: ReportStatus::parse(&status)
+                .ok_or_else(|| format!("Unknown report status: {}", status))?,
+            resolved_at,
+        })
+    }
+}
+
+/// A page of reports
+pub struct ReportConnection {
+    nodes: Vec<Report>,
+    has_next_page: bool,
+}
+
+impl Report {
+    pub fn new(message_id: Vec<u8>, reason: String) -> Self {
+        let timestamp = time_in_millis();
+        let id = snowflake(timestamp);
+        Self {
+            id,
+            message_id,
+            timestamp,
+            reason,
+            status: ReportStatus::Open,
+            resolved_at: None,
+        }
+    }
+
+    /// The raw snowflake ID
+    pub fn snowflake_id(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// The routing key the report is published with when it reaches its current status
+    fn routing_key(&self) -> &'static str {
+        match self.status {
+            ReportStatus::Open => "report.created",
+            ReportStatus::Dismissed => "report.dismissed",
+            ReportStatus::Deleted => "report.deleted",
+        }
+    }
+
+    /// Publish the report to moderators in the background
+    pub(crate) fn publish(self, amqp_client: &AmqpClient) {
+        let routing_key = self.routing_key();
+        amqp_client.produce_in_background(self, Exchange::Moderation, routing_key);
+    }
+
+    /// The report as it is recorded in the audit log
+    fn to_json(&self) -> Value {
+        json!({
+            "id": BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            "messageId": BASE64_URL_SAFE_NO_PAD.encode(&self.message_id),
+            "timestamp": self.timestamp.format(&Rfc3339).ok(),
+            "reason": self.reason,
+            "status": self.status.as_str(),
+            "resolvedAt": self
+                .resolved_at
+                .and_then(|resolved_at| resolved_at.format(&Rfc3339).ok()),
+        })
+    }
+
+    /// Store a report and publish it to moderators. Returns `None` if the reported message does
+    /// not exist.
+    pub async fn create(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        report: Report,
+    ) -> Result<Option<Report>, DatabaseError> {
+        let client = pool.get().await?;
+        let result = client
+            .interact(|client| {
+                client.transaction(|client| {
+                    // Locked so that the message cannot be deleted, resolving its reports, before
+                    // this one is inserted
+                    let message = message_schema::table
+                        .find(&report.message_id)
+                        .select(message_schema::id)
+                        .for_share()
+                        .first::<Vec<u8>>(client)
+                        .optional()?;
+                    if message.is_none() {
+                        return Ok(None);
+                    }
+                    diesel::insert_into(report_schema::table)
+                        .values((
+                            report_schema::id.eq(&report.id),
+                            report_schema::message_id.eq(&report.message_id),
+                            report_schema::timestamp.eq(report.timestamp),
+                            report_schema::reason.eq(&report.reason),
+                            report_schema::status.eq(report.status.as_str()),
+                        ))
+                        .execute(client)?;
+                    QueryResult::Ok(Some(report))
+                })
+            })
+            .await??;
+
+        if let Some(report) = &result {
+            report.clone().publish(amqp_client);
+        }
+        Ok(result)
+    }
+
+    pub async fn find(pool: &Pool, id: Vec<u8>) -> Result<Option<Report>, DatabaseError> {
+        let client = pool.get().await?;
+        let result = client
+            .interact(|client| {
+                report_schema::table
+                    .find(id)
+                    .first::<Report>(client)
+                    .optional()
+            })
+            .await??;
+
+        Ok(result)
+    }
+
+    /// Reports, oldest first, optionally only those with the given status and those after the
+    /// report with ID `after`
+    pub async fn list(
+        pool: &Pool,
+        status: Option<ReportStatus>,
+        after: Option<Vec<u8>>,
+        limit: i64,
+    ) -> Result<ReportConnection, DatabaseError> {
+        let client = pool.get().await?;
+        let mut nodes = client
+            .interact(move |client| {
+                let mut query = report_schema::table
+                    .order(report_schema::id.asc())
+                    .limit(limit + 1)
+                    .into_boxed();
+                if let Some(status) = status {
+                    query = query.filter(report_schema::status.eq(status.as_str()));
+                }
+                if let Some(after) = after {
+                    query = query.filter(report_schema::id.gt(after));
+                }
+                query.load::<Report>(client)
+            })
+            .await??;
+
+        let has_next_page = nodes.len() as i64 > limit;
+        nodes.truncate(limit as usize);
+        Ok(ReportConnection {
+            nodes,
+            has_next_page,
+        })
+    }
+
+    /// Resolve the open reports matching the filter with the given status, record the
+    /// resolutions in the audit log, and return the reports so that they can be published once
+    /// the transaction commits. Should be run in a transaction.
+    pub(crate) fn resolve_rows(
+        client: &mut PgConnection,
+        actor: &Actor,
+        status: ReportStatus,
+        id: Option<Vec<u8>>,
+        message_ids: Option<Vec<Vec<u8>>>,
+    ) -> QueryResult<Vec<Report>> {
+        let mut query = diesel::update(report_schema::table)
+            .filter(report_schema::status.eq(ReportStatus::Open.as_str()))
+            .into_boxed();
+        if let Some(id) = id {
+            query = query.filter(report_schema::id.eq(id));
+        }
+        if let Some(message_ids) = message_ids {
+            query = query.filter(report_schema::message_id.eq_any(message_ids));
+        }
+        let reports = query
+            .set((
+                report_schema::status.eq(status.as_str()),
+                report_schema::resolved_at.eq(OffsetDateTime::now_utc()),
+            ))
+            .get_results::<Report>(client)?;
+
+        let events = reports
+            .iter()
+            .map(|report| {
+                let before = Report {
+                    status: ReportStatus::Open,
+                    resolved_at: None,
+                    ..report.clone()
+                };
+                AuditEvent::new(
+                    actor,
+                    &format!("report.{}", status.as_str()),
+                    "report",
+                    &report.id,
+                    Some(before.to_json()),
+                    Some(report.to_json()),
+                )
+            })
+            .collect();
+        AuditEvent::record(client, events)?;
+        Ok(reports)
+    }
+
+    /// Close an open report without acting on the message, returning the report if it was open
+    pub async fn dismiss(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        actor: &Actor,
+        id: Vec<u8>,
+    ) -> Result<Option<Report>, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let reports = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    Report::resolve_rows(client, &actor, ReportStatus::Dismissed, Some(id), None)
+                })
+            })
+            .await??;
+
+        let report = reports.into_iter().next();
+        if let Some(report) = &report {
+            report.clone().publish(amqp_client);
+        }
+        Ok(report)
+    }
+
+    /// Delete the reported message and close every open report of it, returning the report if
+    /// it was open. The report is locked while the message is deleted, so that it cannot be
+    /// dismissed at the same time.
+    pub async fn delete_message(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        blob_store: &dyn BlobStore,
+        actor: &Actor,
+        id: Vec<u8>,
+    ) -> Result<Option<Report>, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let deleted = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let report = report_schema::table
+                        .find(&id)
+                        .filter(report_schema::status.eq(ReportStatus::Open.as_str()))
+                        .for_update()
+                        .first::<Report>(client)
+                        .optional()?;
+                    match report {
+                        Some(report) => {
+                            let deleted =
+                                Message::delete_audited(client, &actor, report.message_id)?;
+                            QueryResult::Ok(Some((id, deleted)))
+                        }
+                        None => Ok(None),
+                    }
+                })
+            })
+            .await??;
+
+        let (id, deleted) = match deleted {
+            Some(deleted) => deleted,
+            None => return Ok(None),
+        };
+        let report = deleted
+            .reports()
+            .iter()
+            .find(|report| report.id == id)
+            .cloned();
+        Message::deleted(amqp_client, blob_store, deleted).await;
+        Ok(report)
+    }
+}
+
+impl Protobuf for Report {
+    fn try_to_protobuf(self) -> Result<Vec<u8>, AmqpError> {
+        let report = ReportProto {
+            id: self.id,
+            message_id: self.message_id,
+            reason: self.reason,
+            status: self.status.as_str().to_string(),
+            timestamp: (self.timestamp.unix_timestamp_nanos() / 1_000_000) as u64,
+            resolved_at: self
+                .resolved_at
+                .map(|resolved_at| (resolved_at.unix_timestamp_nanos() / 1_000_000) as u64)
+                .unwrap_or_default(),
+            special_fields: SpecialFields::new(),
+        };
+        Ok(report.write_to_bytes()?)
+    }
+
+    fn try_from_protobuf(payload: &[u8]) -> Result<Self, AmqpError> {
+        let report = ReportProto::parse_from_bytes(payload)?;
+        Ok(Self {
+            id: report.id,
+            message_id: report.message_id,
+            timestamp: OffsetDateTime::from_unix_timestamp_nanos(
+                report.timestamp as i128 * 1_000_000,
+            )?,
+            reason: report.reason,
+            status: ReportStatus::parse(&report.status).ok_or_else(|| AmqpError {
+                message: format!("Unknown report status: {}", report.status),
+            })?,
+            resolved_at: match report.resolved_at {
+                0 => None,
+                resolved_at => Some(OffsetDateTime::from_unix_timestamp_nanos(
+                    resolved_at as i128 * 1_000_000,
+                )?),
+            },
+        })
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A report that a message breaks the rules
+impl Report {
+    /// The report's unique ID
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// The ID of the reported message
+    pub fn message_id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.message_id)
+    }
+
+    /// The reported message, unless it has since been deleted
+    pub async fn message(&self, context: &Context) -> Option<Message> {
+        context.message_loader.load(self.message_id.clone()).await
+    }
+
+    /// Why the message was reported
+    pub fn reason(&self) -> String {
+        self.reason.clone()
+    }
+
+    /// Where the report is in review
+    pub fn status(&self) -> ReportStatus {
+        self.status
+    }
+
+    /// The time the report was made
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+
+    /// The time a moderator resolved the report, if they have
+    pub fn resolved_at(&self) -> Option<OffsetDateTime> {
+        self.resolved_at
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A page of reports
+impl ReportConnection {
+    /// The reports on this page
+    pub fn nodes(&self) -> Vec<Report> {
+        self.nodes.clone()
+    }
+
+    /// The cursor to pass as `after` to get the next page
+    pub fn end_cursor(&self) -> Option<String> {
+        self.nodes
+            .last()
+            .map(|report| BASE64_URL_SAFE_NO_PAD.encode(&report.id))
+    }
+
+    /// Whether there are more reports after this page
+    pub fn has_next_page(&self) -> bool {
+        self.has_next_page
+    }
+}
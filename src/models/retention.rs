@@ -0,0 +1,452 @@
+use crate::amqp::{AmqpClient, Protobuf};
+use crate::blob::BlobStore;
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::message::{Deletion, Message};
+use crate::models::{ago, DatabaseError};
+use crate::schema::message as message_schema;
+use crate::schema::message_archive as message_archive_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::prelude::*;
+use diesel::{Insertable, PgConnection, Queryable};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use protobuf::CodedOutputStream;
+use serde_json::{json, Value};
+use std::fs::{create_dir_all, remove_file, rename, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How many messages are archived per transaction, and so per file
+const BATCH_SIZE: i64 = 1000;
+
+/// How many of the most recent archives are included in the retention status
+const RECENT_ARCHIVES: i64 = 10;
+
+/// Limits on how long messages are kept before they are archived and deleted
+#[derive(Clone, Copy)]
+pub struct RetentionPolicy {
+    max_age: Option<Duration>,
+    max_count: Option<i64>,
+}
+
+impl RetentionPolicy {
+    pub fn new(max_age: Option<Duration>, max_count: Option<i64>) -> Self {
+        Self { max_age, max_count }
+    }
+
+    /// Whether the policy ever expires messages
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_count.is_some()
+    }
+
+    /// The ID of the newest message that has outlived the policy. Every message with an ID up
+    /// to and including it has expired.
+    fn cutoff(&self, client: &mut PgConnection) -> QueryResult<Option<Vec<u8>>> {
+        let by_count = match self.max_count {
+            Some(max_count) => message_schema::table
+                .select(message_schema::id)
+                .order(message_schema::id.desc())
+                .offset(max_count)
+                .first::<Vec<u8>>(client)
+                .optional()?,
+            None => None,
+        };
+        // No message can be older than the earliest time that can be represented
+        let by_age = match self.max_age.and_then(ago) {
+            Some(oldest_kept) => message_schema::table
+                .select(message_schema::id)
+                .filter(message_schema::timestamp.lt(oldest_kept))
+                .order(message_schema::id.desc())
+                .first::<Vec<u8>>(client)
+                .optional()?,
+            None => None,
+        };
+        Ok(by_count.max(by_age))
+    }
+}
+
+/// A file of messages that were removed from the database by the retention policy
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = message_archive_schema)]
+pub struct MessageArchive {
+    id: Vec<u8>,
+    timestamp: OffsetDateTime,
+    path: String,
+    message_count: i64,
+    first_message_id: Vec<u8>,
+    last_message_id: Vec<u8>,
+}
+
+impl MessageArchive {
+    fn new(dir: &Path, messages: &[Message]) -> Self {
+        let timestamp = time_in_millis();
+        let id = snowflake(timestamp);
+        let path = dir.join(format!("{}.pb.gz", BASE64_URL_SAFE_NO_PAD.encode(&id)));
+        Self {
+            id,
+            timestamp,
+            path: path.to_string_lossy().to_string(),
+            message_count: messages.len() as i64,
+            first_message_id: messages[0].snowflake_id().to_vec(),
+            last_message_id: messages[messages.len() - 1].snowflake_id().to_vec(),
+        }
+    }
+
+    /// The archive as it is recorded in the audit log
+    fn to_json(&self) -> Value {
+        json!({
+            "id": BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            "timestamp": self.timestamp.format(&Rfc3339).ok(),
+            "path": self.path,
+            "messageCount": self.message_count,
+            "firstMessageId": BASE64_URL_SAFE_NO_PAD.encode(&self.first_message_id),
+            "lastMessageId": BASE64_URL_SAFE_NO_PAD.encode(&self.last_message_id),
+        })
+    }
+
+    /// The path the archive is written to until it is complete
+    fn partial_path(&self) -> PathBuf {
+        Path::new(&self.path).with_extension("gz.partial")
+    }
+
+    /// Write the messages to the archive's partial path as gzipped, length-delimited protobuf,
+    /// the same encoding they are published with. The file only appears at the archive's path
+    /// once [`MessageArchive::complete`] is called.
+    fn write(&self, messages: &[Message]) -> Result<(), DatabaseError> {
+        let partial = self.partial_path();
+        if let Some(dir) = partial.parent() {
+            create_dir_all(dir)?;
+        }
+
+        let mut encoder = GzEncoder::new(File::create(&partial)?, Compression::default());
+        let mut output = CodedOutputStream::new(&mut encoder);
+        for message in messages {
+            let bytes = message
+                .clone()
+                .try_to_protobuf()
+                .map_err(|e| DatabaseError { message: e.message })?;
+            output.write_raw_varint32(bytes.len() as u32)?;
+            output.write_raw_bytes(&bytes)?;
+        }
+        output.flush()?;
+        drop(output);
+        encoder.finish()?.sync_all()?;
+        Ok(())
+    }
+
+    /// Move the written file to the archive's path, and sync its directory so that the move
+    /// survives a crash
+    fn complete(&self) -> Result<(), DatabaseError> {
+        rename(self.partial_path(), &self.path)?;
+        if let Some(dir) = Path::new(&self.path).parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the written file, whether or not it was completed, because the archive was not
+    /// recorded
+    fn discard(&self) {
+        for path in [self.partial_path(), PathBuf::from(&self.path)] {
+            match remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    println!(
+                        "Error removing unrecorded archive {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Archive the oldest batch of messages that have outlived the policy to a file in `dir`,
+    /// then delete them along with their attachments and publish the deletions to subscribers.
+    /// Returns how many messages were archived.
+    ///
+    /// The archive holds attachment metadata but not contents. Rows are locked with
+    /// `FOR UPDATE SKIP LOCKED`, so instances running this at once archive disjoint batches.
+    ///
+    /// The file is moved into place as the last step before the transaction commits, and
+    /// removed if the transaction rolls back, so a recorded archive always has its file. A crash
+    /// before the commit can leave a file that no archive records; its messages are still in
+    /// the database and are archived again.
+    pub async fn archive_expired(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        blob_store: &dyn BlobStore,
+        policy: RetentionPolicy,
+        dir: PathBuf,
+    ) -> Result<usize, DatabaseError> {
+        let client = pool.get().await?;
+        let deletion = client
+            .interact(move |client| {
+                let mut written = None;
+                let result = client.transaction(|client| {
+                    let cutoff = match policy.cutoff(client)? {
+                        Some(cutoff) => cutoff,
+                        None => return Ok(Deletion::default()),
+                    };
+                    let messages = message_schema::table
+                        .filter(message_schema::id.le(cutoff))
+                        .order(message_schema::id.asc())
+                        .limit(BATCH_SIZE)
+                        .for_update()
+                        .skip_locked()
+                        .load::<Message>(client)?;
+                    if messages.is_empty() {
+                        return Ok(Deletion::default());
+                    }
+                    let messages = Message::with_attachments(client, messages)?;
+
+                    let archive = MessageArchive::new(&dir, &messages);
+                    written = Some(archive.clone());
+                    archive.write(&messages)?;
+                    AuditEvent::record(
+                        client,
+                        vec![AuditEvent::new(
+                            &Actor::system(),
+                            "message_archive.create",
+                            "message_archive",
+                            &archive.id,
+                            None,
+                            Some(archive.to_json()),
+                        )],
+                    )?;
+                    diesel::insert_into(message_archive_schema::table)
+                        .values(archive.clone())
+                        .execute(client)?;
+
+                    let ids = messages
+                        .iter()
+                        .map(|message| message.snowflake_id().to_vec())
+                        .collect();
+                    let deletion = Message::delete_rows(client, &Actor::system(), ids)?;
+
+                    archive.complete()?;
+                    Ok::<_, DatabaseError>(deletion)
+                });
+
+                // A rolled back batch is archived again later, so it must leave no file behind
+                if let (Err(_), Some(archive)) = (&result, written) {
+                    archive.discard();
+                }
+                result
+            })
+            .await??;
+
+        let archived = deletion.len();
+        Message::deleted(amqp_client, blob_store, deletion).await;
+        Ok(archived)
+    }
+
+    /// The most recent archives, newest first
+    pub async fn recent(pool: &Pool, limit: i64) -> Result<Vec<MessageArchive>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(move |client| {
+                message_archive_schema::table
+                    .order(message_archive_schema::id.desc())
+                    .limit(limit)
+                    .load::<MessageArchive>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+
+    /// The path of the file on the server's disk, which is not exposed to clients
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A file of messages that were removed from the database by the retention policy
+impl MessageArchive {
+    /// The archive's unique ID
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// How many messages the file contains
+    pub fn message_count(&self) -> i32 {
+        i32::try_from(self.message_count).unwrap_or(i32::MAX)
+    }
+
+    /// The ID of the oldest message in the file
+    pub fn first_message_id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.first_message_id)
+    }
+
+    /// The ID of the newest message in the file
+    pub fn last_message_id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.last_message_id)
+    }
+
+    /// The time the archive was written
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+}
+
+/// The retention policy in effect and how the message table stands against it
+pub struct RetentionStatus {
+    policy: RetentionPolicy,
+    message_count: i64,
+    expired_message_count: i64,
+    oldest_message: Option<OffsetDateTime>,
+    archives: Vec<MessageArchive>,
+}
+
+impl RetentionStatus {
+    pub async fn load(pool: &Pool, policy: RetentionPolicy) -> Result<Self, DatabaseError> {
+        let client = pool.get().await?;
+        let (message_count, expired_message_count, oldest_message) = client
+            .interact(move |client| {
+                let message_count = message_schema::table.count().get_result::<i64>(client)?;
+                let expired_message_count = match policy.cutoff(client)? {
+                    Some(cutoff) => message_schema::table
+                        .filter(message_schema::id.le(cutoff))
+                        .count()
+                        .get_result::<i64>(client)?,
+                    None => 0,
+                };
+                let oldest_message = message_schema::table
+                    .select(message_schema::timestamp)
+                    .order(message_schema::id.asc())
+                    .first::<OffsetDateTime>(client)
+                    .optional()?;
+                QueryResult::Ok((message_count, expired_message_count, oldest_message))
+            })
+            .await??;
+
+        Ok(Self {
+            policy,
+            message_count,
+            expired_message_count,
+            oldest_message,
+            archives: MessageArchive::recent(pool, RECENT_ARCHIVES).await?,
+        })
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// The retention policy in effect and how the message table stands against it
+impl RetentionStatus {
+    /// The age in seconds after which messages are archived, if there is a limit
+    pub fn max_age(&self) -> Option<i32> {
+        self.policy
+            .max_age
+            .map(|max_age| i32::try_from(max_age.as_secs()).unwrap_or(i32::MAX))
+    }
+
+    /// How many of the newest messages are kept, if there is a limit
+    pub fn max_count(&self) -> Option<i32> {
+        self.policy
+            .max_count
+            .map(|max_count| i32::try_from(max_count).unwrap_or(i32::MAX))
+    }
+
+    /// How many messages are stored
+    pub fn message_count(&self) -> i32 {
+        i32::try_from(self.message_count).unwrap_or(i32::MAX)
+    }
+
+    /// How many stored messages have outlived the policy and are waiting to be archived
+    pub fn expired_message_count(&self) -> i32 {
+        i32::try_from(self.expired_message_count).unwrap_or(i32::MAX)
+    }
+
+    /// The time the oldest stored message was sent
+    pub fn oldest_message(&self) -> Option<OffsetDateTime> {
+        self.oldest_message
+    }
+
+    /// The most recent archives, newest first
+    pub fn archives(&self) -> Vec<MessageArchive> {
+        self.archives.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use protobuf::CodedInputStream;
+    use serial_test::parallel;
+
+    #[test]
+    #[parallel]
+    fn test_write() {
+        let dir = std::env::temp_dir().join("syntropic-archive-test");
+        let messages = vec![
+            Message::new("Hello,\nworld!".to_string()),
+            Message::new("Goodbye".to_string()),
+        ];
+        let archive = MessageArchive::new(&dir, &messages);
+        archive.write(&messages).unwrap();
+        assert!(!Path::new(&archive.path).exists());
+        archive.complete().unwrap();
+
+        let mut decoder = GzDecoder::new(File::open(&archive.path).unwrap());
+        let mut input = CodedInputStream::new(&mut decoder);
+        let mut bodies = Vec::new();
+        while !input.eof().unwrap() {
+            let length = input.read_raw_varint32().unwrap();
+            let bytes = input.read_raw_bytes(length).unwrap();
+            bodies.push(Message::try_from_protobuf(&bytes).unwrap().body());
+        }
+        assert_eq!(bodies, ["Hello,\nworld!", "Goodbye"]);
+        assert_eq!(archive.message_count, 2);
+        assert_eq!(archive.first_message_id, messages[0].snowflake_id());
+    }
+
+    #[test]
+    #[parallel]
+    fn test_discard() {
+        let dir = std::env::temp_dir().join("syntropic-archive-test");
+        let messages = vec![Message::new("Rolled back".to_string())];
+        let archive = MessageArchive::new(&dir, &messages);
+        archive.write(&messages).unwrap();
+        archive.discard();
+        assert!(!archive.partial_path().exists());
+        assert!(!Path::new(&archive.path).exists());
+
+        let archive = MessageArchive::new(&dir, &messages);
+        archive.write(&messages).unwrap();
+        archive.complete().unwrap();
+        archive.discard();
+        assert!(!Path::new(&archive.path).exists());
+    }
+
+    #[test]
+    #[parallel]
+    fn test_complete_failure() {
+        let dir = std::env::temp_dir().join("syntropic-archive-test");
+        let messages = vec![Message::new("Not moved".to_string())];
+        let archive = MessageArchive::new(&dir, &messages);
+        archive.write(&messages).unwrap();
+
+        // A directory in the way makes the rename fail
+        create_dir_all(Path::new(&archive.path).join("blocker")).unwrap();
+        assert!(archive.complete().is_err());
+        archive.discard();
+        assert!(!archive.partial_path().exists());
+        std::fs::remove_dir_all(&archive.path).unwrap();
+    }
+
+    #[test]
+    #[parallel]
+    fn test_ago_overflow() {
+        assert!(ago(Duration::from_secs(60)).is_some());
+        assert!(ago(Duration::from_secs(u64::MAX)).is_none());
+    }
+}
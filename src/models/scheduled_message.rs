@@ -0,0 +1,282 @@
+use crate::amqp::{AmqpClient, Exchange};
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::flagged_message::FlaggedMessage;
+use crate::models::message::Message;
+use crate::models::DatabaseError;
+use crate::moderation::Moderator;
+use crate::schema::scheduled_message as scheduled_message_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::prelude::*;
+use diesel::{Insertable, Queryable};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+/// How many due messages one instance sends per transaction
+const BATCH_SIZE: i64 = 100;
+
+/// How long a sent message may go without a confirmed publish before it is published again
+const PUBLISH_TIMEOUT: Duration = Duration::minutes(1);
+
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = scheduled_message_schema)]
+pub struct ScheduledMessage {
+    id: Vec<u8>,
+    timestamp: OffsetDateTime,
+    send_at: OffsetDateTime,
+    body: String,
+    /// Set once the message is sent, until its publish is confirmed
+    message_id: Option<Vec<u8>>,
+    publish_attempted_at: Option<OffsetDateTime>,
+}
+
+impl ScheduledMessage {
+    pub fn new(body: String, send_at: OffsetDateTime) -> Self {
+        let timestamp = time_in_millis();
+        let id = snowflake(timestamp);
+        Self {
+            id,
+            timestamp,
+            send_at,
+            body,
+            message_id: None,
+            publish_attempted_at: None,
+        }
+    }
+
+    /// The scheduled message as it is recorded in the audit log, without its body
+    fn to_json(&self) -> Value {
+        json!({
+            "id": BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            "timestamp": self.timestamp.format(&Rfc3339).ok(),
+            "sendAt": self.send_at.format(&Rfc3339).ok(),
+        })
+    }
+
+    pub async fn create(
+        pool: &Pool,
+        scheduled: ScheduledMessage,
+    ) -> Result<ScheduledMessage, DatabaseError> {
+        let client = pool.get().await?;
+        let scheduled = client
+            .interact(|client| {
+                diesel::insert_into(scheduled_message_schema::table)
+                    .values(scheduled)
+                    .get_result(client)
+            })
+            .await??;
+
+        Ok(scheduled)
+    }
+
+    /// Cancel a scheduled message, returning whether it was still pending
+    pub async fn cancel(pool: &Pool, actor: &Actor, id: Vec<u8>) -> Result<bool, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let cancelled = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let cancelled = diesel::delete(
+                        scheduled_message_schema::table
+                            .filter(scheduled_message_schema::id.eq(id))
+                            .filter(scheduled_message_schema::message_id.is_null()),
+                    )
+                    .get_result::<ScheduledMessage>(client)
+                    .optional()?;
+                    if let Some(scheduled) = &cancelled {
+                        AuditEvent::record(
+                            client,
+                            vec![AuditEvent::new(
+                                &actor,
+                                "scheduled_message.cancel",
+                                "scheduled_message",
+                                &scheduled.id,
+                                Some(scheduled.to_json()),
+                                None,
+                            )],
+                        )?;
+                    }
+                    QueryResult::Ok(cancelled)
+                })
+            })
+            .await??;
+
+        Ok(cancelled.is_some())
+    }
+
+    /// Pending messages, soonest first
+    pub async fn list(pool: &Pool) -> Result<Vec<ScheduledMessage>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(|client| {
+                scheduled_message_schema::table
+                    .filter(scheduled_message_schema::message_id.is_null())
+                    .order(scheduled_message_schema::send_at.asc())
+                    .load::<ScheduledMessage>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+
+    /// Move messages that are due into the message table, then publish them. Returns how many
+    /// messages were sent.
+    ///
+    /// Due rows are locked with `FOR UPDATE SKIP LOCKED`, so when several instances run this at
+    /// once each row is claimed by exactly one of them, and its message is stored in the same
+    /// transaction. The row is kept as an outbox entry pointing at the message until publishing
+    /// is confirmed. If the server stops in between, or publishing fails, the message is
+    /// published again once `PUBLISH_TIMEOUT` has passed, so subscribers receive it at least
+    /// once.
+    ///
+    /// Bodies are moderated again when they are sent, in case the filters changed since the
+    /// message was scheduled. Messages that are now rejected are dropped.
+    pub async fn send_due(
+        pool: &Pool,
+        amqp_client: &AmqpClient,
+        moderator: Arc<Moderator>,
+    ) -> Result<usize, DatabaseError> {
+        let client = pool.get().await?;
+        let (outbox, sent) = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let now = OffsetDateTime::now_utc();
+                    let unpublished = scheduled_message_schema::table
+                        .filter(scheduled_message_schema::message_id.is_not_null())
+                        .filter(
+                            scheduled_message_schema::publish_attempted_at
+                                .le(now - PUBLISH_TIMEOUT),
+                        )
+                        .limit(BATCH_SIZE)
+                        .for_update()
+                        .skip_locked()
+                        .load::<ScheduledMessage>(client)?;
+                    let due = scheduled_message_schema::table
+                        .filter(scheduled_message_schema::message_id.is_null())
+                        .filter(scheduled_message_schema::send_at.le(now))
+                        .order(scheduled_message_schema::send_at.asc())
+                        .limit(BATCH_SIZE)
+                        .for_update()
+                        .skip_locked()
+                        .load::<ScheduledMessage>(client)?;
+
+                    // Messages whose publish was never confirmed, such as by a run that was
+                    // stopped, are published again
+                    let unpublished_ids = unpublished
+                        .iter()
+                        .map(|scheduled| scheduled.id.clone())
+                        .collect::<Vec<_>>();
+                    diesel::update(
+                        scheduled_message_schema::table
+                            .filter(scheduled_message_schema::id.eq_any(unpublished_ids)),
+                    )
+                    .set(scheduled_message_schema::publish_attempted_at.eq(now))
+                    .execute(client)?;
+                    let mut outbox = Vec::with_capacity(unpublished.len() + due.len());
+                    let message_ids = unpublished
+                        .iter()
+                        .filter_map(|scheduled| scheduled.message_id.clone())
+                        .collect();
+                    for message in Message::find_all(client, message_ids)? {
+                        let scheduled = unpublished.iter().find(|scheduled| {
+                            scheduled.message_id.as_deref() == Some(message.snowflake_id())
+                        });
+                        if let Some(scheduled) = scheduled {
+                            outbox.push((scheduled.id.clone(), message));
+                        }
+                    }
+
+                    let mut sent = 0;
+                    for scheduled in due {
+                        let moderated = match moderator.moderate(scheduled.body.clone()) {
+                            Ok(moderated) => moderated,
+                            Err(err) => {
+                                println!("Dropping scheduled message: {}", err);
+                                diesel::delete(scheduled_message_schema::table.find(&scheduled.id))
+                                    .execute(client)?;
+                                AuditEvent::record(
+                                    client,
+                                    vec![AuditEvent::new(
+                                        &Actor::system(),
+                                        "scheduled_message.reject",
+                                        "scheduled_message",
+                                        &scheduled.id,
+                                        Some(scheduled.to_json()),
+                                        None,
+                                    )],
+                                )?;
+                                continue;
+                            }
+                        };
+                        let (body, flags) = moderated.into_parts();
+                        let message = Message::insert(client, Message::new(body), &[])?;
+                        if !flags.is_empty() {
+                            FlaggedMessage::flag(client, message.snowflake_id(), flags)?;
+                        }
+                        diesel::update(scheduled_message_schema::table.find(&scheduled.id))
+                            .set((
+                                scheduled_message_schema::message_id
+                                    .eq(message.snowflake_id().to_vec()),
+                                scheduled_message_schema::publish_attempted_at.eq(now),
+                            ))
+                            .execute(client)?;
+                        outbox.push((scheduled.id, message));
+                        sent += 1;
+                    }
+                    Ok::<_, DatabaseError>((outbox, sent))
+                })
+            })
+            .await??;
+
+        let mut published = Vec::with_capacity(outbox.len());
+        for (id, message) in outbox {
+            match amqp_client
+                .produce(message, Exchange::Messages, "message")
+                .await
+            {
+                Ok(()) => published.push(id),
+                Err(err) => println!("Error sending scheduled message: {}", err),
+            }
+        }
+        if !published.is_empty() {
+            client
+                .interact(|client| {
+                    diesel::delete(
+                        scheduled_message_schema::table
+                            .filter(scheduled_message_schema::id.eq_any(published)),
+                    )
+                    .execute(client)
+                })
+                .await??;
+        }
+        Ok(sent)
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A message that will be sent at a later time
+impl ScheduledMessage {
+    /// The scheduled message's unique ID, which is not the ID of the message once it is sent
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// The text of the message
+    pub fn body(&self) -> String {
+        self.body.clone()
+    }
+
+    /// The time the message will be sent
+    pub fn send_at(&self) -> OffsetDateTime {
+        self.send_at
+    }
+
+    /// The time the message was scheduled
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+}
@@ -0,0 +1,343 @@
+use crate::models::audit_event::{Actor, AuditEvent};
+use crate::models::DatabaseError;
+use crate::schema::webhook as webhook_schema;
+use crate::schema::webhook_delivery as webhook_delivery_schema;
+use crate::snowflake::{snowflake, time_in_millis};
+use crate::Context;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use deadpool_diesel::postgres::Pool;
+use diesel::prelude::*;
+use diesel::{Insertable, Queryable};
+use hmac::{Hmac, Mac};
+use juniper::FieldResult;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// The events a webhook can subscribe to
+pub const EVENTS: [&str; 2] = ["message.sent", "message.deleted"];
+
+#[derive(Queryable, Insertable, Clone)]
+#[diesel(table_name = webhook_schema)]
+pub struct Webhook {
+    id: Vec<u8>,
+    timestamp: OffsetDateTime,
+    url: String,
+    secret: String,
+    events: Vec<String>,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: String, events: Vec<String>) -> Self {
+        let timestamp = time_in_millis();
+        let id = snowflake(timestamp);
+        Self {
+            id,
+            timestamp,
+            url,
+            secret,
+            events,
+        }
+    }
+
+    /// Whether the webhook is subscribed to the given event. A webhook with no events is
+    /// subscribed to all of them.
+    pub fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+
+    /// The `X-Syntropic-Signature` header for a payload: its HMAC-SHA256, keyed with the
+    /// webhook's secret
+    pub fn sign(&self, payload: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(payload);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// The `X-Syntropic-Delivery` header for an event about a message. It is the same on every
+    /// attempt and every redelivery of the event, so receivers can use it to drop duplicates.
+    pub fn delivery_id(&self, event: &str, message_id: &str) -> String {
+        format!(
+            "{}.{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            event,
+            message_id
+        )
+    }
+
+    pub fn target(&self) -> &str {
+        &self.url
+    }
+
+    /// The webhook as it is recorded in the audit log, without its secret
+    fn to_json(&self) -> Value {
+        json!({
+            "id": BASE64_URL_SAFE_NO_PAD.encode(&self.id),
+            "timestamp": self.timestamp.format(&Rfc3339).ok(),
+            "url": self.url,
+            "events": self.events,
+        })
+    }
+
+    pub async fn create(
+        pool: &Pool,
+        actor: &Actor,
+        webhook: Webhook,
+    ) -> Result<Webhook, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let webhook = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let webhook = diesel::insert_into(webhook_schema::table)
+                        .values(webhook)
+                        .get_result::<Webhook>(client)?;
+                    AuditEvent::record(
+                        client,
+                        vec![AuditEvent::new(
+                            &actor,
+                            "webhook.create",
+                            "webhook",
+                            &webhook.id,
+                            None,
+                            Some(webhook.to_json()),
+                        )],
+                    )?;
+                    QueryResult::Ok(webhook)
+                })
+            })
+            .await??;
+
+        Ok(webhook)
+    }
+
+    /// Delete a webhook and its delivery log, returning whether it existed
+    pub async fn delete(pool: &Pool, actor: &Actor, id: Vec<u8>) -> Result<bool, DatabaseError> {
+        let actor = actor.clone();
+        let client = pool.get().await?;
+        let deleted = client
+            .interact(move |client| {
+                client.transaction(|client| {
+                    let deleted = diesel::delete(webhook_schema::table.find(id))
+                        .get_result::<Webhook>(client)
+                        .optional()?;
+                    if let Some(webhook) = &deleted {
+                        AuditEvent::record(
+                            client,
+                            vec![AuditEvent::new(
+                                &actor,
+                                "webhook.delete",
+                                "webhook",
+                                &webhook.id,
+                                Some(webhook.to_json()),
+                                None,
+                            )],
+                        )?;
+                    }
+                    QueryResult::Ok(deleted)
+                })
+            })
+            .await??;
+
+        Ok(deleted.is_some())
+    }
+
+    pub async fn list(pool: &Pool) -> Result<Vec<Webhook>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(|client| {
+                webhook_schema::table
+                    .order(webhook_schema::id.asc())
+                    .load::<Webhook>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+
+    /// The webhooks subscribed to the given event
+    pub async fn subscribed(pool: &Pool, event: &str) -> Result<Vec<Webhook>, DatabaseError> {
+        Ok(Webhook::list(pool)
+            .await?
+            .into_iter()
+            .filter(|webhook| webhook.wants(event))
+            .collect())
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// A URL that is sent a signed POST request for each subscribed event
+impl Webhook {
+    /// The webhook's unique ID
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// The URL events are sent to
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// The events the webhook is subscribed to, or empty for all events
+    pub fn events(&self) -> Vec<String> {
+        self.events.clone()
+    }
+
+    /// The time the webhook was created
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+
+    /// The most recent delivery attempts, newest first
+    pub async fn deliveries(&self, context: &Context) -> FieldResult<Vec<WebhookDelivery>> {
+        Ok(WebhookDelivery::list(&context.pool, self.id.clone()).await?)
+    }
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = webhook_delivery_schema)]
+pub struct WebhookDelivery {
+    id: Vec<u8>,
+    webhook_id: Vec<u8>,
+    timestamp: OffsetDateTime,
+    event: String,
+    attempt: i32,
+    status_code: Option<i32>,
+    error: Option<String>,
+}
+
+impl WebhookDelivery {
+    pub fn new(
+        webhook: &Webhook,
+        event: &str,
+        attempt: i32,
+        status_code: Option<i32>,
+        error: Option<String>,
+    ) -> Self {
+        let timestamp = time_in_millis();
+        let id = snowflake(timestamp);
+        Self {
+            id,
+            webhook_id: webhook.id.clone(),
+            timestamp,
+            event: event.to_string(),
+            attempt,
+            status_code,
+            error,
+        }
+    }
+
+    pub async fn record(pool: &Pool, delivery: WebhookDelivery) -> Result<(), DatabaseError> {
+        let client = pool.get().await?;
+        client
+            .interact(|client| {
+                diesel::insert_into(webhook_delivery_schema::table)
+                    .values(delivery)
+                    .execute(client)
+            })
+            .await??;
+
+        Ok(())
+    }
+
+    pub async fn list(
+        pool: &Pool,
+        webhook_id: Vec<u8>,
+    ) -> Result<Vec<WebhookDelivery>, DatabaseError> {
+        let client = pool.get().await?;
+        let results = client
+            .interact(|client| {
+                webhook_delivery_schema::table
+                    .filter(webhook_delivery_schema::webhook_id.eq(webhook_id))
+                    .order(webhook_delivery_schema::id.desc())
+                    .limit(100)
+                    .load::<WebhookDelivery>(client)
+            })
+            .await??;
+
+        Ok(results)
+    }
+}
+
+#[juniper::graphql_object(Context = crate::Context)]
+/// An attempt to deliver an event to a webhook
+impl WebhookDelivery {
+    /// The delivery's unique ID
+    pub fn id(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+    }
+
+    /// The event that was delivered
+    pub fn event(&self) -> String {
+        self.event.clone()
+    }
+
+    /// The attempt number, starting at 1
+    pub fn attempt(&self) -> i32 {
+        self.attempt
+    }
+
+    /// The HTTP status code of the response, if one was received
+    pub fn status_code(&self) -> Option<i32> {
+        self.status_code
+    }
+
+    /// Why the attempt failed, if it did
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    /// Whether the webhook accepted the event
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The time of the attempt
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::parallel;
+
+    #[test]
+    #[parallel]
+    fn test_sign() {
+        let webhook = Webhook::new("https://example.com".to_string(), "key".to_string(), vec![]);
+        assert_eq!(
+            webhook.sign(b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn test_wants() {
+        let all = Webhook::new("https://example.com".to_string(), "".to_string(), vec![]);
+        assert!(all.wants("message.sent"));
+        let some = Webhook::new(
+            "https://example.com".to_string(),
+            "".to_string(),
+            vec!["message.deleted".to_string()],
+        );
+        assert!(!some.wants("message.sent"));
+        assert!(some.wants("message.deleted"));
+    }
+
+    #[test]
+    #[parallel]
+    fn test_delivery_id() {
+        let webhook = Webhook::new("https://example.com".to_string(), "".to_string(), vec![]);
+        let id = webhook.delivery_id("message.sent", "AAAAAAAAAAA");
+        assert_eq!(id, webhook.delivery_id("message.sent", "AAAAAAAAAAA"));
+        assert_ne!(id, webhook.delivery_id("message.deleted", "AAAAAAAAAAA"));
+        let other = Webhook::new("https://example.com".to_string(), "".to_string(), vec![]);
+        assert_ne!(id, other.delivery_id("message.sent", "AAAAAAAAAAA"));
+    }
+}
@@ -0,0 +1,496 @@
+use crate::markdown;
+use once_cell::sync::Lazy;
+use pulldown_cmark::{Event, Tag};
+use regex::{Captures, Regex};
+use reqwest::Url;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Range;
+
+#[derive(Debug)]
+pub struct ModerationError {
+    pub message: String,
+}
+
+impl Display for ModerationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl<T: Error> From<T> for ModerationError {
+    fn from(e: T) -> Self {
+        Self {
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Links written as plain text, which Markdown leaves alone but clients may still link
+static TEXT_LINKS: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\b(?:https?:|www\.)[^\s<>()\[\]]+").unwrap());
+
+/// Relative links are resolved against these, so that they can be told apart from links to
+/// other hosts. Both schemes are used, because a link such as `https:\host` is relative on a
+/// page served over https but not on one served over http.
+static RELATIVE_BASES: Lazy<[Url; 2]> = Lazy::new(|| {
+    ["http", "https"].map(|scheme| Url::parse(&format!("{}://relative.invalid/", scheme)).unwrap())
+});
+
+/// What to do with a message that a filter matches
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Refuse to send the message
+    Reject,
+    /// Send the message with the matching content masked
+    Mask,
+    /// Send the message and add it to the review queue
+    Flag,
+}
+
+/// A check that message bodies are run through before they are stored
+pub trait Filter: Send + Sync {
+    /// A short name for the filter, shown to senders of rejected messages and to reviewers
+    fn name(&self) -> &str;
+    fn action(&self) -> Action;
+    fn matches(&self, body: &str) -> bool;
+    /// The body with the matching content masked
+    fn mask(&self, body: &str) -> String;
+}
+
+/// Matches a regular expression, masking each match with asterisks
+pub struct PatternFilter {
+    name: String,
+    pattern: Regex,
+    action: Action,
+}
+
+impl PatternFilter {
+    /// Matches any of the words, ignoring case
+    pub fn words(name: String, words: &[String], action: Action) -> Result<Self, ModerationError> {
+        if words.is_empty() {
+            return Err(ModerationError {
+                message: format!("The {} filter has no words", name),
+            });
+        }
+        let words: Vec<String> = words.iter().map(|word| regex::escape(word)).collect();
+        Ok(Self {
+            name,
+            pattern: Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|")))?,
+            action,
+        })
+    }
+
+    /// Matches any of the regular expressions
+    pub fn patterns(
+        name: String,
+        patterns: &[String],
+        action: Action,
+    ) -> Result<Self, ModerationError> {
+        if patterns.is_empty() {
+            return Err(ModerationError {
+                message: format!("The {} filter has no patterns", name),
+            });
+        }
+        let patterns: Vec<String> = patterns
+            .iter()
+            .map(|pattern| format!("(?:{})", pattern))
+            .collect();
+        Ok(Self {
+            name,
+            pattern: Regex::new(&patterns.join("|"))?,
+            action,
+        })
+    }
+}
+
+impl Filter for PatternFilter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn action(&self) -> Action {
+        self.action
+    }
+
+    fn matches(&self, body: &str) -> bool {
+        self.pattern.is_match(body)
+    }
+
+    fn mask(&self, body: &str) -> String {
+        self.pattern
+            .replace_all(body, |captures: &Captures| {
+                "*".repeat(captures[0].chars().count())
+            })
+            .to_string()
+    }
+}
+
+/// Matches links to any host not on the allow list, or to any host if the list is empty.
+/// Relative links are allowed, while scheme-relative links such as `//host/path` are not.
+///
+/// Link and image destinations are taken from the parsed Markdown, so that they are checked
+/// as they are rendered, and plain text that looks like a link is checked as well.
+pub struct LinkFilter {
+    name: String,
+    allow: Vec<String>,
+    action: Action,
+}
+
+impl LinkFilter {
+    pub fn new(name: String, allow: Vec<String>, action: Action) -> Self {
+        Self {
+            name,
+            allow: allow.iter().map(|host| host.to_lowercase()).collect(),
+            action,
+        }
+    }
+
+    /// Whether the link points to an allowed host, has no host, or is relative. Links that
+    /// cannot be parsed are not allowed.
+    fn is_allowed(&self, url: Option<Url>) -> bool {
+        let url = match url {
+            Some(url) => url,
+            None => return false,
+        };
+        let host = match url.host_str() {
+            Some(host) if Some(host) != RELATIVE_BASES[0].host_str() => host,
+            _ => return true,
+        };
+        let host = host.trim_start_matches("www.");
+        self.allow
+            .iter()
+            .any(|allowed| host == allowed || host.ends_with(&format!(".{}", allowed)))
+    }
+
+    /// The ranges of the body that hold links the filter does not allow, outermost first
+    fn disallowed(&self, body: &str) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        // Text can be split across several events, such as around escapes and entities
+        let mut text: Option<(Range<usize>, String)> = None;
+        for (event, range) in markdown::parser(body).into_offset_iter() {
+            if let Event::Text(content) = &event {
+                match &mut text {
+                    Some((run, run_text)) => {
+                        run.end = range.end;
+                        run_text.push_str(content);
+                    }
+                    None => text = Some((range, content.to_string())),
+                }
+                continue;
+            }
+            self.disallowed_text(body, text.take(), &mut ranges);
+            match event {
+                Event::Start(Tag::Link(_, url, _)) | Event::Start(Tag::Image(_, url, _))
+                    if !RELATIVE_BASES
+                        .iter()
+                        .all(|base| self.is_allowed(base.join(url.trim()).ok())) =>
+                {
+                    ranges.push(range)
+                }
+                _ => (),
+            }
+        }
+        self.disallowed_text(body, text, &mut ranges);
+        ranges.sort_by_key(|range| (range.start, Reverse(range.end)));
+        ranges
+    }
+
+    /// Add the ranges of links written as plain text that are not allowed. If the text was
+    /// unescaped, its offsets no longer match the body, so all of it is covered.
+    fn disallowed_text(
+        &self,
+        body: &str,
+        text: Option<(Range<usize>, String)>,
+        ranges: &mut Vec<Range<usize>>,
+    ) {
+        let (run, text) = match text {
+            Some(text) => text,
+            None => return,
+        };
+        for link in TEXT_LINKS.find_iter(&text) {
+            let url = link.as_str().trim_end_matches(|c| ".,;:!?'\"".contains(c));
+            let allowed = match url.get(..4) {
+                Some(prefix) if prefix.eq_ignore_ascii_case("www.") => {
+                    self.is_allowed(Url::parse(&format!("http://{}", url)).ok())
+                }
+                _ => self.is_allowed(Url::parse(url).ok()),
+            };
+            if allowed {
+                continue;
+            }
+            if body[run.clone()] != text {
+                ranges.push(run);
+                return;
+            }
+            ranges.push(run.start + link.start()..run.start + link.start() + url.len());
+        }
+    }
+}
+
+impl Filter for LinkFilter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn action(&self) -> Action {
+        self.action
+    }
+
+    fn matches(&self, body: &str) -> bool {
+        !self.disallowed(body).is_empty()
+    }
+
+    fn mask(&self, body: &str) -> String {
+        let mut masked = String::new();
+        let mut end = 0;
+        for range in self.disallowed(body) {
+            // Links nested in a link that was already removed
+            if range.start < end {
+                continue;
+            }
+            masked.push_str(&body[end..range.start]);
+            masked.push_str("[link removed]");
+            end = range.end;
+        }
+        masked.push_str(&body[end..]);
+        masked
+    }
+}
+
+/// A message body that has passed moderation. Only [`Moderator::moderate`] makes one, so
+/// requiring it proves that a body went through the filters.
+pub struct Moderated {
+    body: String,
+    /// The names of the filters that flagged the message for review
+    flags: Vec<String>,
+}
+
+impl Moderated {
+    /// The body and the names of the filters that flagged it
+    pub fn into_parts(self) -> (String, Vec<String>) {
+        (self.body, self.flags)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Rule {
+    Words {
+        name: Option<String>,
+        words: Vec<String>,
+        action: Action,
+    },
+    Regex {
+        name: Option<String>,
+        patterns: Vec<String>,
+        action: Action,
+    },
+    Links {
+        name: Option<String>,
+        #[serde(default)]
+        allow: Vec<String>,
+        action: Action,
+    },
+}
+
+#[derive(Deserialize)]
+struct Config {
+    rules: Vec<Rule>,
+}
+
+/// Runs message bodies through each filter in order. Masks are applied cumulatively, and the
+/// first filter that rejects a message stops it.
+#[derive(Default)]
+pub struct Moderator {
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl Moderator {
+    pub fn new(filters: Vec<Box<dyn Filter>>) -> Self {
+        Self { filters }
+    }
+
+    /// Build the filters from a JSON config, such as
+    /// `{"rules": [{"type": "words", "words": ["darn"], "action": "mask"}]}`. Rule types are
+    /// `words`, `regex` (with `patterns`) and `links` (with an optional `allow` list of hosts).
+    pub fn from_config(config: &str) -> Result<Self, ModerationError> {
+        let config: Config = serde_json::from_str(config)?;
+        let filters = config
+            .rules
+            .into_iter()
+            .map(|rule| -> Result<Box<dyn Filter>, ModerationError> {
+                Ok(match rule {
+                    Rule::Words {
+                        name,
+                        words,
+                        action,
+                    } => Box::new(PatternFilter::words(
+                        name.unwrap_or("words".to_string()),
+                        &words,
+                        action,
+                    )?),
+                    Rule::Regex {
+                        name,
+                        patterns,
+                        action,
+                    } => Box::new(PatternFilter::patterns(
+                        name.unwrap_or("regex".to_string()),
+                        &patterns,
+                        action,
+                    )?),
+                    Rule::Links {
+                        name,
+                        allow,
+                        action,
+                    } => Box::new(LinkFilter::new(
+                        name.unwrap_or("links".to_string()),
+                        allow,
+                        action,
+                    )),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { filters })
+    }
+
+    /// Add a custom filter, run after the existing ones
+    pub fn add(&mut self, filter: Box<dyn Filter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn moderate(&self, body: String) -> Result<Moderated, ModerationError> {
+        let mut moderated = Moderated {
+            body,
+            flags: Vec::new(),
+        };
+        for filter in &self.filters {
+            if !filter.matches(&moderated.body) {
+                continue;
+            }
+            match filter.action() {
+                Action::Reject => {
+                    return Err(ModerationError {
+                        message: format!("Message rejected by the {} filter", filter.name()),
+                    })
+                }
+                Action::Mask => moderated.body = filter.mask(&moderated.body),
+                Action::Flag => moderated.flags.push(filter.name().to_string()),
+            }
+        }
+        Ok(moderated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::parallel;
+
+    fn moderator() -> Moderator {
+        Moderator::from_config(
+            r#"{"rules": [
+                {"type": "words", "words": ["darn", "heck"], "action": "mask"},
+                {"type": "regex", "name": "card", "patterns": ["\\d{4}-\\d{4}-\\d{4}-\\d{4}"], "action": "reject"},
+                {"type": "links", "allow": ["example.com"], "action": "flag"}
+            ]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    #[parallel]
+    fn test_mask() {
+        let moderated = moderator().moderate("Darn it, heckle".to_string()).unwrap();
+        assert_eq!(moderated.body, "**** it, heckle");
+        assert!(moderated.flags.is_empty());
+    }
+
+    #[test]
+    #[parallel]
+    fn test_reject() {
+        let result = moderator().moderate("My card is 1234-5678-9012-3456".to_string());
+        assert_eq!(
+            result.err().unwrap().message,
+            "Message rejected by the card filter"
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn test_links() {
+        let moderator = moderator();
+        let moderated = moderator
+            .moderate("See https://docs.example.com/page".to_string())
+            .unwrap();
+        assert!(moderated.flags.is_empty());
+        let moderated = moderator
+            .moderate("See [this](https://evil.test/x)".to_string())
+            .unwrap();
+        assert_eq!(moderated.flags, ["links"]);
+
+        let filter = LinkFilter::new("links".to_string(), Vec::new(), Action::Mask);
+        assert_eq!(
+            filter.mask("Go to www.evil.test now"),
+            "Go to [link removed] now"
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn test_link_bypasses() {
+        let filter = LinkFilter::new(
+            "links".to_string(),
+            vec!["example.com".to_string()],
+            Action::Mask,
+        );
+        assert!(!filter.matches("[docs](https://docs.example.com/page) and [home](/relative)"));
+        assert!(!filter.matches("<https://example.com>, https://example.com."));
+        for body in [
+            "https://example.com@evil.test",
+            "[x](https://example.com@evil.test)",
+            "[x](https&#58;//evil.test)",
+            "[x](//evil.test)",
+            "[x](\\\\\\\\evil.test)",
+            "https:\\\\evil.test",
+            "[x](https:\\\\evil.test)",
+            "![x](http://evil.test/pixel.png)",
+            "<http://evil.test>",
+            "[x][ref]\n\n[ref]: https://evil.test",
+            "https&#58;//evil.test",
+        ] {
+            assert!(filter.matches(body), "{} was not matched", body);
+        }
+
+        assert_eq!(
+            filter.mask("See [x](//evil.test) or https://example.com@evil.test."),
+            "See [link removed] or [link removed]."
+        );
+        assert_eq!(
+            filter.mask("[https://evil.test](https://evil.test) and [docs](https://example.com)"),
+            "[link removed] and [docs](https://example.com)"
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn test_empty_rules() {
+        let result = Moderator::from_config(
+            r#"{"rules": [{"type": "words", "words": [], "action": "reject"}]}"#,
+        );
+        assert_eq!(
+            result.err().unwrap().message,
+            "The words filter has no words"
+        );
+        let result = Moderator::from_config(
+            r#"{"rules": [{"type": "regex", "patterns": [], "action": "reject"}]}"#,
+        );
+        assert_eq!(
+            result.err().unwrap().message,
+            "The regex filter has no patterns"
+        );
+    }
+}
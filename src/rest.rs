@@ -0,0 +1,348 @@
+use crate::blob::BlobError;
+use crate::models::attachment::Attachment;
+use crate::models::audit_event::{AuditEvent, AuditEventFilter};
+use crate::models::incoming_webhook::IncomingWebhook;
+use crate::models::message::{Message, INVALID_ATTACHMENTS};
+use crate::models::DatabaseError;
+use crate::moderation::ModerationError;
+use crate::{is_admin, max_attachment_size, AppData};
+use actix_web::http::header::{
+    ContentDisposition, ContentType, DispositionParam, DispositionType, CONTENT_TYPE, LINK,
+    LOCATION, X_CONTENT_TYPE_OPTIONS,
+};
+use actix_web::http::StatusCode;
+use actix_web::web::{resource, Bytes, Json, Path, PayloadConfig, Query, ServiceConfig};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use juniper::futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt::{Display, Formatter};
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+const OPENAPI: &str = include_str!("rest/openapi.json");
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 100;
+/// The longest expiry in seconds, the same as GraphQL allows, so that expiry times cannot
+/// overflow
+const MAX_EXPIRES_IN: i64 = i32::MAX as i64;
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    NotFound,
+    Internal(String),
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::BadRequest(message) => write!(f, "{}", message),
+            ApiError::Unauthorized => write!(f, "Admin access required"),
+            ApiError::NotFound => write!(f, "Not found"),
+            ApiError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(e: DatabaseError) -> Self {
+        ApiError::Internal(e.message)
+    }
+}
+
+impl From<ModerationError> for ApiError {
+    fn from(e: ModerationError) -> Self {
+        ApiError::BadRequest(e.message)
+    }
+}
+
+impl From<BlobError> for ApiError {
+    fn from(e: BlobError) -> Self {
+        ApiError::Internal(e.message)
+    }
+}
+
+fn decode_id(id: &str) -> Result<Vec<u8>, ApiError> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(id)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid ID: {}", e)))
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    before: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendMessage {
+    body: String,
+    #[serde(default)]
+    attachments: Vec<String>,
+    expires_in: Option<i64>,
+    client_message_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuditExportParams {
+    actor: Option<String>,
+    action: Option<String>,
+    target_type: Option<String>,
+    target_id: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UploadParams {
+    filename: Option<String>,
+}
+
+/// A page of messages, newest first. A `Link` header with `rel="next"` points to the next page
+/// if there is one.
+async fn list_messages(data: AppData, params: Query<ListParams>) -> Result<HttpResponse, ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_LIMIT
+        )));
+    }
+    let before = params.before.as_deref().map(decode_id).transpose()?;
+
+    let mut messages = Message::list(&data.0, before, Some(limit + 1)).await?;
+    let mut response = HttpResponse::Ok();
+    if messages.len() as i64 > limit {
+        messages.truncate(limit as usize);
+        if let Some(last) = messages.last() {
+            response.insert_header((
+                LINK,
+                format!(
+                    "</api/v1/messages?before={}&limit={}>; rel=\"next\"",
+                    last.id(),
+                    limit
+                ),
+            ));
+        }
+    }
+
+    Ok(response.json(messages))
+}
+
+async fn get_message(data: AppData, id: Path<String>) -> Result<HttpResponse, ApiError> {
+    let id = decode_id(&id)?;
+    match Message::loader(data.0.clone()).load(id).await {
+        Some(message) => Ok(HttpResponse::Ok().json(message)),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+async fn send_message(data: AppData, body: Json<SendMessage>) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    let attachments = body
+        .attachments
+        .iter()
+        .map(|id| decode_id(id))
+        .collect::<Result<_, _>>()?;
+    let expires_in = match body.expires_in {
+        Some(seconds) if !(1..=MAX_EXPIRES_IN).contains(&seconds) => {
+            return Err(ApiError::BadRequest(format!(
+                "expiresIn must be between 1 and {}",
+                MAX_EXPIRES_IN
+            )))
+        }
+        expires_in => expires_in.map(Duration::seconds),
+    };
+    let message = Message::send(
+        &data.0,
+        &data.1,
+        data.3.moderate(body.body)?,
+        attachments,
+        expires_in,
+        body.client_message_id,
+    )
+    .await?
+    .ok_or_else(|| ApiError::BadRequest(INVALID_ATTACHMENTS.to_string()))?;
+    Ok(HttpResponse::Created()
+        .insert_header((LOCATION, format!("/api/v1/messages/{}", message.id())))
+        .json(message))
+}
+
+#[derive(Deserialize)]
+struct IncomingMessage {
+    text: String,
+}
+
+/// Send a message through an incoming webhook, authenticated by the token in the path
+async fn incoming_webhook(
+    data: AppData,
+    token: Path<String>,
+    body: Json<IncomingMessage>,
+) -> Result<HttpResponse, ApiError> {
+    if IncomingWebhook::find_by_token(&data.0, token.into_inner())
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound);
+    }
+
+    let message = Message::send(
+        &data.0,
+        &data.1,
+        data.3.moderate(body.into_inner().text)?,
+        Vec::new(),
+        None,
+        None,
+    )
+    .await?
+    .ok_or_else(|| ApiError::BadRequest(INVALID_ATTACHMENTS.to_string()))?;
+    Ok(HttpResponse::Created()
+        .insert_header((LOCATION, format!("/api/v1/messages/{}", message.id())))
+        .json(message))
+}
+
+/// Upload the request body as an attachment, to be included in a message that is sent later.
+/// Attachments that are never sent are deleted by [`crate::jobs::delete_unused_attachments`].
+async fn upload_attachment(
+    data: AppData,
+    req: HttpRequest,
+    params: Query<UploadParams>,
+    body: Bytes,
+) -> Result<HttpResponse, ApiError> {
+    if body.is_empty() {
+        return Err(ApiError::BadRequest("Attachment is empty".to_string()));
+    }
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let attachment = Attachment::new(params.into_inner().filename, content_type, &body);
+    data.2
+        .put(&attachment.key(), attachment.mime_type(), body.to_vec())
+        .await?;
+    let attachment = match Attachment::create(&data.0, attachment.clone()).await {
+        Ok(attachment) => attachment,
+        Err(e) => {
+            data.2.delete(&attachment.key()).await.ok();
+            return Err(e.into());
+        }
+    };
+
+    Ok(HttpResponse::Created()
+        .insert_header((LOCATION, attachment.url()))
+        .json(attachment))
+}
+
+/// Download an attachment that has been sent with a message
+async fn download_attachment(data: AppData, id: Path<String>) -> Result<HttpResponse, ApiError> {
+    let attachment = Attachment::find(&data.0, decode_id(&id)?)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let contents = data
+        .2
+        .get(&attachment.key())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    // Uploaders choose the content type, so browsers must download attachments rather than
+    // render them from this origin, which would let an HTML attachment run scripts
+    Ok(HttpResponse::Ok()
+        .content_type(attachment.mime_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: attachment
+                .file_name()
+                .map(|filename| DispositionParam::Filename(filename.to_string()))
+                .into_iter()
+                .collect(),
+        })
+        .insert_header((X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(contents))
+}
+
+fn parse_time(name: &str, time: Option<String>) -> Result<Option<OffsetDateTime>, ApiError> {
+    time.map(|time| OffsetDateTime::parse(&time, &Rfc3339))
+        .transpose()
+        .map_err(|e| ApiError::BadRequest(format!("Invalid {}: {}", name, e)))
+}
+
+/// Stream the matching audit events, oldest first, as newline-delimited JSON. Requires the
+/// admin token.
+async fn export_audit_events(
+    req: HttpRequest,
+    data: AppData,
+    params: Query<AuditExportParams>,
+) -> Result<HttpResponse, ApiError> {
+    if !is_admin(&req) {
+        return Err(ApiError::Unauthorized);
+    }
+    let params = params.into_inner();
+    let filter = AuditEventFilter {
+        since: parse_time("since", params.since)?,
+        until: parse_time("until", params.until)?,
+        actor: params.actor,
+        action: params.action,
+        target_type: params.target_type,
+        target_id: params.target_id,
+    };
+
+    // An error partway through ends the response early, so a truncated export is detectable
+    let body = AuditEvent::export(data.0.clone(), filter).map(|event| {
+        let event = event.map_err(ApiError::from)?;
+        let mut line = serde_json::to_vec(&event).map_err(|e| ApiError::Internal(e.to_string()))?;
+        line.push(b'\n');
+        Ok::<_, actix_web::Error>(Bytes::from(line))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body))
+}
+
+async fn openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(OPENAPI)
+}
+
+pub fn configure(cfg: &mut ServiceConfig) {
+    cfg.service(
+        resource("/messages")
+            .route(web::get().to(list_messages))
+            .route(web::post().to(send_message)),
+    )
+    .service(resource("/messages/{id}").route(web::get().to(get_message)))
+    .service(resource("/hooks/{token}").route(web::post().to(incoming_webhook)))
+    .service(
+        resource("/attachments")
+            .app_data(PayloadConfig::new(max_attachment_size()))
+            .route(web::post().to(upload_attachment)),
+    )
+    .service(resource("/attachments/{id}").route(web::get().to(download_attachment)))
+    .service(resource("/audit-events/export").route(web::get().to(export_audit_events)))
+    .service(resource("/openapi.json").route(web::get().to(openapi)));
+}
@@ -1,9 +1,136 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    attachment (id) {
+        id -> Bytea,
+        message_id -> Nullable<Bytea>,
+        timestamp -> Timestamptz,
+        filename -> Nullable<Text>,
+        content_type -> Text,
+        size -> Int8,
+        checksum -> Text,
+    }
+}
+
+diesel::table! {
+    flagged_message (id) {
+        id -> Bytea,
+        message_id -> Bytea,
+        timestamp -> Timestamptz,
+        reasons -> Array<Text>,
+    }
+}
+
+diesel::table! {
+    audit_event (seq) {
+        seq -> Int8,
+        timestamp -> Timestamptz,
+        actor -> Text,
+        action -> Text,
+        target_type -> Text,
+        target_id -> Text,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        request_id -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    incoming_webhook (id) {
+        id -> Bytea,
+        timestamp -> Timestamptz,
+        name -> Text,
+        token_hash -> Text,
+    }
+}
+
 diesel::table! {
     message (id) {
         id -> Bytea,
         timestamp -> Timestamptz,
         body -> Text,
+        body_html -> Nullable<Text>,
+        expires_at -> Nullable<Timestamptz>,
+        client_message_id -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    message_archive (id) {
+        id -> Bytea,
+        timestamp -> Timestamptz,
+        path -> Text,
+        message_count -> Int8,
+        first_message_id -> Bytea,
+        last_message_id -> Bytea,
     }
 }
+
+diesel::table! {
+    message_change (seq) {
+        seq -> Int8,
+        txid -> Int8,
+        message_id -> Bytea,
+        kind -> Text,
+        timestamp -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    message_change_compaction (id) {
+        id -> Bool,
+        through_txid -> Int8,
+        through -> Int8,
+    }
+}
+
+diesel::table! {
+    report (id) {
+        id -> Bytea,
+        message_id -> Bytea,
+        timestamp -> Timestamptz,
+        reason -> Text,
+        status -> Text,
+        resolved_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    scheduled_message (id) {
+        id -> Bytea,
+        timestamp -> Timestamptz,
+        send_at -> Timestamptz,
+        body -> Text,
+        message_id -> Nullable<Bytea>,
+        publish_attempted_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    webhook (id) {
+        id -> Bytea,
+        timestamp -> Timestamptz,
+        url -> Text,
+        secret -> Text,
+        events -> Array<Text>,
+    }
+}
+
+diesel::table! {
+    webhook_delivery (id) {
+        id -> Bytea,
+        webhook_id -> Bytea,
+        timestamp -> Timestamptz,
+        event -> Text,
+        attempt -> Int4,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Text>,
+    }
+}
+
+diesel::joinable!(attachment -> message (message_id));
+diesel::joinable!(flagged_message -> message (message_id));
+diesel::joinable!(scheduled_message -> message (message_id));
+diesel::joinable!(webhook_delivery -> webhook (webhook_id));
+
+diesel::allow_tables_to_appear_in_same_query!(message, webhook, webhook_delivery,);
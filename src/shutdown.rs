@@ -0,0 +1,280 @@
+use crate::{shutdown_timeout, AppData};
+use actix_rt::task::JoinHandle;
+use actix_web::body::{BodyStream, BoxBody, MessageBody};
+use actix_web::dev::ServerHandle;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use juniper::futures::future::{join_all, poll_fn};
+use juniper::futures::stream::unfold;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+/// Tells background tasks that the server is shutting down
+#[derive(Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+
+    fn start(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn has_started(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until shutdown has started
+    pub async fn started(&self) {
+        let mut started = self.0.subscribe();
+        while !*started.borrow() {
+            if started.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The websocket close code telling clients that the server is restarting, so they should
+/// reconnect
+const SERVICE_RESTART: u16 = 1012;
+
+/// A websocket close frame as a server sends it, unmasked. The reason must fit in one byte of
+/// length, as control frames are at most 125 bytes.
+fn close_frame(code: u16, reason: &str) -> Bytes {
+    let mut frame = vec![0x88, (reason.len() + 2) as u8];
+    frame.extend_from_slice(&code.to_be_bytes());
+    frame.extend_from_slice(reason.as_bytes());
+    Bytes::from(frame)
+}
+
+/// End a websocket response with a `1012 service restart` close frame once shutdown starts, so
+/// that clients reconnect instead of waiting for the connection to drop.
+pub(crate) fn close_on_shutdown(response: HttpResponse, shutdown: Shutdown) -> HttpResponse {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return response;
+    }
+    // juniper_actix runs the connection as an actix-web-actors websocket actor and gives the
+    // handler no way to reach it, so the close frame cannot be sent through the actor. Instead
+    // it is written into the response body, which is the actor's encoded output. This is safe:
+    // - The actor's context encodes each frame whole before yielding it, so every chunk of the
+    //   body ends on a frame boundary and the close frame never lands inside another frame.
+    // - The close frame is the last thing written. The actor's body is dropped with it, which
+    //   stops the actor, so no frame can follow the close frame.
+    // - The frame is unmasked, as server frames must be, and uses no extension bits, since
+    //   actix-web-actors negotiates no extensions.
+    response.map_body(|_, body| {
+        let frames = unfold(Some(body), move |body| {
+            let shutdown = shutdown.clone();
+            async move {
+                let mut body = body?;
+                tokio::select! {
+                    frame = poll_fn(|cx| Pin::new(&mut body).poll_next(cx)) => {
+                        frame.map(|frame| (frame, Some(body)))
+                    }
+                    _ = shutdown.started() => {
+                        Some((Ok(close_frame(SERVICE_RESTART, "service restart")), None))
+                    }
+                }
+            }
+        });
+        BoxBody::new(BodyStream::new(frames))
+    })
+}
+
+/// Wait for SIGINT or SIGTERM
+async fn signal_received() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+/// Wait for a shutdown signal, then stop the server and its background tasks and release their
+/// resources within `SHUTDOWN_TIMEOUT` seconds.
+///
+/// Subscriptions are completed first, and websocket clients are sent a `1012 service restart`
+/// close frame, so that they reconnect elsewhere while the server stops accepting connections
+/// and finishes in-flight requests. Background tasks are
+/// told to stop and finish the work they have started, then pending AMQP publishes are awaited.
+/// Whatever is left when the timeout is reached is abandoned, and the AMQP connections and the
+/// database pool are closed.
+pub async fn graceful(
+    data: AppData,
+    handle: ServerHandle,
+    shutdown: Shutdown,
+    tasks: Vec<JoinHandle<()>>,
+) {
+    signal_received().await;
+    println!("Shutting down");
+
+    let stopped = async {
+        shutdown.start();
+        data.1.stop_consuming();
+        handle.stop(true).await;
+        join_all(tasks).await;
+        data.1.drain().await;
+    };
+    if timeout(shutdown_timeout(), stopped).await.is_err() {
+        println!(
+            "Shutdown timeout reached with {} publishes pending",
+            data.1.pending()
+        );
+    }
+
+    data.1.close().await;
+    data.0.close();
+}
+
+/// The server's graceful shutdown timeout, in whole seconds
+pub fn server_timeout() -> u64 {
+    shutdown_timeout().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{configure, data};
+    use actix_web::{App, HttpServer};
+    use serial_test::{parallel, serial};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread::spawn;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    /// Read one unmasked frame sent by the server, returning its opcode and payload
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        let length = match header[1] & 0x7f {
+            126 => {
+                let mut length = [0; 2];
+                stream.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            127 => {
+                let mut length = [0; 8];
+                stream.read_exact(&mut length).unwrap();
+                u64::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).unwrap();
+        (header[0] & 0x0f, payload)
+    }
+
+    /// Send a text frame, masked as client frames must be
+    fn write_text(stream: &mut TcpStream, text: &str) {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | text.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(text.bytes().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        stream.write_all(&frame).unwrap();
+    }
+
+    /// Open a graphql-ws connection, report once it is acknowledged, then return the code of
+    /// the close frame the server sends
+    fn graphql_ws_client(addr: SocketAddr, acknowledged: oneshot::Sender<()>) -> u16 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        write!(
+            stream,
+            "GET /subscriptions HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Protocol: graphql-ws\r\n\r\n",
+            addr
+        )
+        .unwrap();
+
+        // Read the handshake response a byte at a time, so that no frame is read with it
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+
+        write_text(&mut stream, r#"{"type":"connection_init","payload":{}}"#);
+        loop {
+            let (opcode, payload) = read_frame(&mut stream);
+            assert_eq!(opcode, 0x1);
+            if String::from_utf8(payload)
+                .unwrap()
+                .contains("connection_ack")
+            {
+                break;
+            }
+        }
+        acknowledged.send(()).unwrap();
+
+        // Keep-alive messages may arrive before the close frame
+        loop {
+            let (opcode, payload) = read_frame(&mut stream);
+            if opcode == 0x8 {
+                return u16::from_be_bytes([payload[0], payload[1]]);
+            }
+        }
+    }
+
+    #[test]
+    #[parallel]
+    fn test_close_frame() {
+        let frame = close_frame(SERVICE_RESTART, "service restart");
+        assert_eq!(&frame[..4], [0x88, 17, 0x03, 0xf4]);
+        assert_eq!(&frame[4..], b"service restart");
+    }
+
+    #[actix_rt::test]
+    #[serial]
+    async fn test_graphql_ws_closed_on_shutdown() {
+        let data = data().await;
+        let shutdown = Shutdown::new();
+        let app_shutdown = shutdown.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .app_data(app_shutdown.clone())
+                .configure(configure)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let (acknowledged, connected) = oneshot::channel();
+        let (closed, code) = oneshot::channel();
+        spawn(move || closed.send(graphql_ws_client(addr, acknowledged)));
+        connected.await.unwrap();
+
+        shutdown.start();
+        assert_eq!(code.await.unwrap(), SERVICE_RESTART);
+        handle.stop(false).await;
+    }
+}
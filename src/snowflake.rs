@@ -13,6 +13,15 @@ pub fn snowflake(timestamp: OffsetDateTime) -> Vec<u8> {
     (millis << 20 | random).to_be_bytes().to_vec()
 }
 
+/// The time a snowflake ID was generated and its random bits, or `None` if it is not a
+/// snowflake ID
+pub fn decode(id: &[u8]) -> Option<(OffsetDateTime, u32)> {
+    let id = u64::from_be_bytes(id.try_into().ok()?);
+    let timestamp =
+        OffsetDateTime::from_unix_timestamp_nanos((id >> 20) as i128 * 1_000_000).ok()?;
+    Some((timestamp, (id & ((1 << 20) - 1)) as u32))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -31,4 +40,15 @@ mod tests {
         assert_eq!(id[4], 192);
         println!("{:?}", id);
     }
+
+    #[test]
+    #[parallel]
+    fn test_decode() {
+        let timestamp = time_in_millis();
+        let id = snowflake(timestamp);
+        let (decoded, random) = decode(&id).unwrap();
+        assert_eq!(decoded, timestamp);
+        assert!(random < 1 << 20);
+        assert!(decode(&id[1..]).is_none());
+    }
 }
@@ -0,0 +1,343 @@
+use crate::amqp::Exchange;
+use crate::models::message::{DeletedMessage, Message};
+use crate::models::message_change::{ChangeKind, MessageChange, SyncCursor, SyncResult};
+use crate::AppData;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{CacheControl, CacheDirective, ContentEncoding};
+use actix_web::web::{Bytes, Data};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use deadpool_diesel::postgres::Pool;
+use juniper::futures::future::ready;
+use juniper::futures::stream::{once, select, unfold, LocalBoxStream};
+use juniper::futures::StreamExt;
+use serde_json::json;
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The most missed changes replayed on one connection, and the most loaded per query
+const MAX_REPLAY: i64 = 1000;
+
+/// Sent after a replay that stopped at `MAX_REPLAY` changes, before the stream ends
+const REPLAY_TRUNCATED: &str = "event: replay.truncated\ndata: {}\n\n";
+
+const HEARTBEAT: &str = ": heartbeat\n\n";
+
+/// The most updates a connection can fall behind the change feed before it loads the changes
+/// it missed by itself
+const FEED_CAPACITY: usize = 64;
+
+/// Why the change feed loads changes from the log
+#[derive(PartialEq, Eq)]
+enum Wake {
+    /// A message was sent or deleted
+    Changed,
+    /// The heartbeat interval passed. Changes held back by a long-running transaction are
+    /// picked up then, if nothing else changes first.
+    Heartbeat,
+}
+
+fn event(message: &Message, cursor: SyncCursor) -> String {
+    format!(
+        "id: {}\nevent: message\ndata: {}\n\n",
+        cursor,
+        serde_json::to_string(message).unwrap_or_default()
+    )
+}
+
+fn deletion_event(change: &MessageChange) -> String {
+    format!(
+        "id: {}\nevent: message.deleted\ndata: {}\n\n",
+        change.cursor(),
+        json!({ "id": change.message_id() })
+    )
+}
+
+/// Sent when the client's `Last-Event-ID` is no longer in the change log, so that it refetches
+/// every message
+fn resync_event(cursor: SyncCursor) -> String {
+    format!("id: {}\nevent: resync\ndata: {{}}\n\n", cursor)
+}
+
+/// The events for a page of changes. Changes to messages that have since been deleted or have
+/// expired send no event, so the page ends with a bare event ID, which moves the client's
+/// `Last-Event-ID` without dispatching an event.
+fn events(result: &SyncResult) -> String {
+    events_after(result, SyncCursor::default())
+}
+
+/// The events for the changes in a page that come after `cursor`
+fn events_after(result: &SyncResult, cursor: SyncCursor) -> String {
+    let changes: Vec<MessageChange> = result
+        .changes()
+        .into_iter()
+        .filter(|change| change.cursor() > cursor)
+        .collect();
+    let mut events = String::new();
+    let mut sent = None;
+    for change in &changes {
+        match (change.kind(), change.message()) {
+            (ChangeKind::Created, Some(message)) => {
+                events.push_str(&event(&message, change.cursor()))
+            }
+            (ChangeKind::Created, None) => continue,
+            (ChangeKind::Deleted, _) => events.push_str(&deletion_event(change)),
+        }
+        sent = Some(change.cursor());
+    }
+    if !changes.is_empty() && sent != Some(result.next_cursor()) {
+        events.push_str(&format!("id: {}\n\n", result.next_cursor()));
+    }
+    events
+}
+
+/// The events for every change after `cursor`, and the cursor to continue from
+async fn load(pool: &Pool, mut cursor: SyncCursor) -> Option<(String, SyncCursor)> {
+    let mut loaded = String::new();
+    loop {
+        let result = MessageChange::since(pool, Some(cursor), MAX_REPLAY)
+            .await
+            .map_err(|err| println!("Error loading message changes: {}", err))
+            .ok()?;
+        if result.resync() {
+            loaded.push_str(&resync_event(result.next_cursor()));
+        }
+        loaded.push_str(&events(&result));
+        cursor = result.next_cursor();
+        if !result.has_more() {
+            return Some((loaded, cursor));
+        }
+    }
+}
+
+/// What the change feed sends to each connection
+enum Update {
+    /// A page of changes, loaded from `from`
+    Changes {
+        from: SyncCursor,
+        result: SyncResult,
+    },
+    /// The heartbeat interval passed
+    Heartbeat,
+}
+
+/// Loads new changes from the log once for every connection on a worker, instead of once per
+/// connection. It runs while any connection is subscribed, and until the server stops consuming.
+#[derive(Default)]
+pub struct ChangeFeed {
+    /// A receiver held only to subscribe new connections with, and the task that sends to it
+    running: RefCell<Option<(Receiver<Arc<Update>>, JoinHandle<()>)>>,
+}
+
+impl ChangeFeed {
+    fn resubscribe(&self) -> Option<Receiver<Arc<Update>>> {
+        match &*self.running.borrow() {
+            Some((receiver, task)) if !task.is_finished() => Some(receiver.resubscribe()),
+            _ => None,
+        }
+    }
+
+    /// Receive the updates loaded after this call, starting the feed if it is not running
+    async fn subscribe(&self, data: &AppData) -> Result<Receiver<Arc<Update>>, Error> {
+        if let Some(receiver) = self.resubscribe() {
+            return Ok(receiver);
+        }
+
+        // Consume before loading the latest cursor, so that no change made in between is left
+        // waiting for a heartbeat
+        let messages = data
+            .1
+            .consume::<Message>(Exchange::Messages, "message")
+            .await
+            .map_err(ErrorInternalServerError)?;
+        let deletions = data
+            .1
+            .consume::<DeletedMessage>(Exchange::Messages, "message.deleted")
+            .await
+            .map_err(ErrorInternalServerError)?;
+        let latest = MessageChange::since(&data.0, None, MAX_REPLAY)
+            .await
+            .map_err(ErrorInternalServerError)?;
+
+        // Another connection may have started the feed in the meantime
+        if let Some(receiver) = self.resubscribe() {
+            return Ok(receiver);
+        }
+
+        // The stream ends once the server stops consuming, even though heartbeats would not
+        let changes = select(messages.map(|_| ()), deletions.map(|_| ()))
+            .map(|_| Some(Wake::Changed))
+            .chain(once(ready(None)));
+        let heartbeats = unfold(interval(HEARTBEAT_INTERVAL), |mut interval| async move {
+            interval.tick().await;
+            Some((Some(Wake::Heartbeat), interval))
+        });
+        let wakes = select(changes, heartbeats)
+            .take_while(|wake| ready(wake.is_some()))
+            .filter_map(ready)
+            // Changes made together are loaded together
+            .ready_chunks(MAX_REPLAY as usize)
+            .boxed_local();
+
+        let (sender, receiver) = channel(FEED_CAPACITY);
+        let subscribed = receiver.resubscribe();
+        let task = actix_rt::spawn(feed(data.0.clone(), latest.next_cursor(), wakes, sender));
+        *self.running.borrow_mut() = Some((receiver, task));
+        Ok(subscribed)
+    }
+}
+
+/// Load the changes after `cursor` on each wake-up and send them to every subscriber
+async fn feed(
+    pool: Pool,
+    mut cursor: SyncCursor,
+    mut wakes: LocalBoxStream<'static, Vec<Wake>>,
+    sender: Sender<Arc<Update>>,
+) {
+    while let Some(wake) = wakes.next().await {
+        // The receiver held by `ChangeFeed` is not a connection
+        if sender.receiver_count() <= 1 {
+            return;
+        }
+        loop {
+            let result = match MessageChange::since(&pool, Some(cursor), MAX_REPLAY).await {
+                Ok(result) => result,
+                Err(err) => {
+                    // The changes are loaded again on the next wake-up
+                    println!("Error loading message changes: {}", err);
+                    break;
+                }
+            };
+            let from = cursor;
+            let has_more = result.has_more();
+            cursor = result.next_cursor();
+            if cursor != from {
+                // Sending fails only when no connection is subscribed
+                let _ = sender.send(Arc::new(Update::Changes { from, result }));
+            }
+            if !has_more {
+                break;
+            }
+        }
+        if wake.contains(&Wake::Heartbeat) {
+            let _ = sender.send(Arc::new(Update::Heartbeat));
+        }
+    }
+}
+
+/// The events for an update from the change feed, and the cursor to continue from. A connection
+/// whose cursor is behind the start of the update loads the changes it missed by itself.
+async fn receive(
+    pool: &Pool,
+    update: Result<Arc<Update>, RecvError>,
+    cursor: SyncCursor,
+) -> Option<(String, SyncCursor)> {
+    match update.as_deref() {
+        Ok(Update::Heartbeat) => Some((HEARTBEAT.to_string(), cursor)),
+        Ok(Update::Changes { result, .. }) if result.next_cursor() <= cursor => {
+            Some((String::new(), cursor))
+        }
+        Ok(Update::Changes { from, result }) if *from <= cursor && !result.resync() => {
+            Some((events_after(result, cursor), result.next_cursor()))
+        }
+        Ok(Update::Changes { .. }) | Err(RecvError::Lagged(_)) => load(pool, cursor).await,
+        Err(RecvError::Closed) => None,
+    }
+}
+
+/// Stream the events of `Subscription::message_received` and `Subscription::message_deleted`
+/// as server-sent events.
+///
+/// Events are read from the change log, so each event ID is a sync cursor, and a client that
+/// reconnects with a `Last-Event-ID` header is sent every change it missed, in the order the
+/// changes were committed. At most `MAX_REPLAY` changes are replayed. If more were missed, a
+/// `replay.truncated` event is sent and the stream ends, so that the client reconnects and the
+/// replay continues from the last change it received. If the changes since `Last-Event-ID` have
+/// been compacted away, or it is not a cursor, a `resync` event is sent instead and the client
+/// should refetch all messages.
+///
+/// New changes are loaded by the worker's `ChangeFeed`, which messages published by the server
+/// prompt to load. Loading also happens with each heartbeat comment, which is sent every
+/// `HEARTBEAT_INTERVAL` to keep the connection open through proxies.
+pub async fn events_route(
+    req: HttpRequest,
+    data: AppData,
+    feed: Data<ChangeFeed>,
+) -> Result<HttpResponse, Error> {
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .map(|id| id.to_str().ok().and_then(SyncCursor::parse));
+
+    // Subscribe before loading missed changes, so that no change made in between is left waiting
+    // for a heartbeat
+    let updates = feed.subscribe(&data).await?;
+    let missed = MessageChange::since(&data.0, last_event_id.flatten(), MAX_REPLAY)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut replay = match (missed.resync(), last_event_id) {
+        (true, Some(_)) => resync_event(missed.next_cursor()),
+        // A new client starts from the latest change
+        (true, None) => format!("id: {}\n\n", missed.next_cursor()),
+        (false, _) => events(&missed),
+    };
+    if missed.has_more() {
+        replay.push_str(REPLAY_TRUNCATED);
+        let body = once(ready(Ok::<_, Infallible>(Bytes::from(replay))));
+        return Ok(response(body));
+    }
+
+    let pool = data.0.clone();
+    let live = unfold(
+        (pool, missed.next_cursor(), updates),
+        |(pool, cursor, mut updates)| async move {
+            let update = updates.recv().await;
+            let (events, cursor) = receive(&pool, update, cursor).await?;
+            Some((events, (pool, cursor, updates)))
+        },
+    );
+
+    let body = once(ready(replay))
+        .chain(live)
+        .filter(|events| ready(!events.is_empty()))
+        .map(|events| Ok::<_, Infallible>(Bytes::from(events)));
+    Ok(response(body))
+}
+
+fn response(
+    body: impl juniper::futures::Stream<Item = Result<Bytes, Infallible>> + 'static,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .insert_header(ContentEncoding::Identity)
+        .streaming(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::parallel;
+
+    #[test]
+    #[parallel]
+    fn test_event() {
+        let message = Message::new("Hello,\nworld!".to_string());
+        let cursor = SyncCursor::parse("7.42").unwrap();
+        let event = event(&message, cursor);
+        let lines: Vec<&str> = event.split('\n').collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "id: 7.42");
+        assert_eq!(lines[1], "event: message");
+        assert!(lines[2].starts_with("data: {"));
+        assert_eq!(&lines[3..], ["", ""]);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::amqp::{AmqpClient, Exchange, Pending};
+use crate::models::message::{DeletedMessage, Message};
+use crate::models::webhook::{Webhook, WebhookDelivery};
+use deadpool_diesel::postgres::Pool;
+use juniper::futures::future::join_all;
+use juniper::futures::{join, StreamExt};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::redirect::Policy;
+use reqwest::{Client, RequestBuilder, Url};
+use serde::Serialize;
+use serde_json::json;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::sleep;
+
+const MAX_ATTEMPTS: i32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many events of each kind are delivered at once
+const CONCURRENT_EVENTS: usize = 32;
+/// How many events of each kind an instance takes from the queue before acknowledging them
+const PREFETCH: u16 = CONCURRENT_EVENTS as u16;
+
+/// Deliver message events to subscribed webhooks until the AMQP client stops consuming, then
+/// finish the deliveries in progress.
+///
+/// Events are consumed from queues shared by every instance of the server, so each event is
+/// delivered once no matter how many instances are running. An event is acknowledged only once
+/// every delivery of it has succeeded or run out of attempts. If the server stops first, the
+/// broker redelivers the event, so webhooks receive each event at least once. Every attempt
+/// carries the same `X-Syntropic-Delivery` header, so receivers can drop duplicates.
+pub async fn run(pool: Pool, amqp_client: AmqpClient) {
+    let messages = amqp_client
+        .consume_shared::<Message>("webhooks", Exchange::Messages, "message", PREFETCH)
+        .await;
+    let deletions = amqp_client
+        .consume_shared::<DeletedMessage>(
+            "webhooks.deleted",
+            Exchange::Messages,
+            "message.deleted",
+            PREFETCH,
+        )
+        .await;
+    let (messages, deletions) = match (messages, deletions) {
+        (Ok(messages), Ok(deletions)) => (messages, deletions),
+        (Err(err), _) | (_, Err(err)) => {
+            println!("Error consuming messages for webhooks: {}", err);
+            return;
+        }
+    };
+    join!(
+        messages.for_each_concurrent(CONCURRENT_EVENTS, |message| {
+            dispatch(&pool, "message.sent", message.payload.id(), message)
+        }),
+        deletions.for_each_concurrent(CONCURRENT_EVENTS, |deletion| {
+            dispatch(&pool, "message.deleted", deletion.payload.id(), deletion)
+        }),
+    );
+}
+
+/// Deliver an event about the message with ID `message_id` to every subscribed webhook, then
+/// acknowledge it. If the webhooks cannot be loaded, the event is requeued after a delay.
+async fn dispatch<T: Serialize>(
+    pool: &Pool,
+    event: &'static str,
+    message_id: String,
+    data: Pending<T>,
+) {
+    let webhooks = match Webhook::subscribed(pool, event).await {
+        Ok(webhooks) => webhooks,
+        Err(err) => {
+            println!("Error loading webhooks: {}", err);
+            sleep(INITIAL_BACKOFF).await;
+            data.requeue().await;
+            return;
+        }
+    };
+    let payload = payload(event, &data.payload);
+
+    join_all(
+        webhooks
+            .iter()
+            .map(|webhook| deliver(pool, webhook, event, &message_id, &payload)),
+    )
+    .await;
+    data.ack().await;
+}
+
+/// The JSON body delivered for an event
+fn payload<T: Serialize>(event: &str, data: &T) -> String {
+    json!({ "event": event, "data": data }).to_string()
+}
+
+/// Whether the address can be reached from the public internet. A leaked admin token must not
+/// let webhooks reach the server's own network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network", shared address space, IETF protocol assignments, benchmarking
+                // and reserved
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let ipv4 = |high: u16, low: u16| {
+                IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+            };
+            // IPv4-mapped and IPv4-compatible addresses, including :: and ::1
+            if let Some(embedded) = ip.to_ipv4() {
+                return is_public(IpAddr::V4(embedded));
+            }
+            // NAT64 addresses reach the embedded IPv4 address. Where the local-use prefix embeds
+            // it depends on the prefix length, so those are refused outright.
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public(ipv4(segments[6], segments[7]));
+            }
+            if segments[..3] == [0x64, 0xff9b, 1] {
+                return false;
+            }
+            // 6to4 addresses tunnel to the IPv4 address that follows the prefix
+            if segments[0] == 0x2002 {
+                return is_public(ipv4(segments[1], segments[2]));
+            }
+            // Teredo addresses embed the server's IPv4 address and the client's, inverted
+            if segments[..2] == [0x2001, 0] {
+                return is_public(ipv4(segments[2], segments[3]))
+                    && is_public(ipv4(!segments[6], !segments[7]));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local, link-local and documentation
+                || segments[0] & 0xfe00 == 0xfc00
+                || segments[0] & 0xffc0 == 0xfe80
+                || segments[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+/// A client that connects to the URL's host only at the public addresses it resolves to now.
+/// The addresses are checked at delivery time, rather than when the webhook is registered, so
+/// that a host cannot be pointed at a private address later. Redirects are not followed and
+/// proxies are not used, because either would connect to a host that has not been checked.
+async fn client_for(url: &str) -> Result<Client, String> {
+    let url = Url::parse(url).map_err(|err| err.to_string())?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| "Webhook URL has no host".to_string())?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| "Webhook URL has no port".to_string())?;
+    // IPv6 hosts are bracketed in URLs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|err| format!("Error resolving {}: {}", host, err))?
+        .collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err(format!("{} does not resolve to a public address", host));
+    }
+
+    Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(Policy::none())
+        .no_proxy()
+        .resolve_to_addrs(host, &addrs)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+/// The request that delivers a payload to a webhook, with its event, delivery ID and signature
+fn post(
+    http: &Client,
+    webhook: &Webhook,
+    event: &str,
+    delivery_id: &str,
+    payload: &str,
+) -> RequestBuilder {
+    http.post(webhook.target())
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Syntropic-Event", event)
+        .header("X-Syntropic-Delivery", delivery_id)
+        .header("X-Syntropic-Signature", webhook.sign(payload.as_bytes()))
+        .body(payload.to_string())
+}
+
+/// POST the payload to the webhook, retrying with exponential backoff until it responds with a
+/// success status or `MAX_ATTEMPTS` is reached. Every attempt is recorded in the delivery log.
+async fn deliver(pool: &Pool, webhook: &Webhook, event: &str, message_id: &str, payload: &str) {
+    let delivery_id = webhook.delivery_id(event, message_id);
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let response = match client_for(webhook.target()).await {
+            Ok(http) => post(&http, webhook, event, &delivery_id, payload)
+                .send()
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err),
+        };
+        let (status_code, error) = match response {
+            Ok(response) if response.status().is_success() => {
+                (Some(response.status().as_u16() as i32), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16() as i32),
+                Some(format!("Unexpected status {}", response.status())),
+            ),
+            Err(err) => (None, Some(err)),
+        };
+        let success = error.is_none();
+
+        let delivery = WebhookDelivery::new(webhook, event, attempt, status_code, error);
+        if let Err(err) = WebhookDelivery::record(pool, delivery).await {
+            println!("Error recording webhook delivery: {}", err);
+        }
+
+        if success {
+            return;
+        }
+        if attempt < MAX_ATTEMPTS {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac::{Hmac, Mac};
+    use serde_json::Value;
+    use serial_test::parallel;
+    use sha2::Sha256;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{spawn, JoinHandle};
+
+    /// Accept one HTTP request on a local port and answer it with 200. The thread returns the
+    /// request's headers, with lowercase names, and its body.
+    fn listen() -> (SocketAddr, JoinHandle<(HashMap<String, String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                let read = stream.read(&mut buf).unwrap();
+                assert!(read > 0, "connection closed before the request ended");
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let (head, body) = match text.split_once("\r\n\r\n") {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let headers: HashMap<String, String> = head
+                    .lines()
+                    .skip(1)
+                    .filter_map(|line| line.split_once(": "))
+                    .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+                    .collect();
+                let length = headers
+                    .get("content-length")
+                    .map_or(0, |length| length.parse().unwrap());
+                if body.len() >= length {
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .unwrap();
+                    return (headers, body.to_string());
+                }
+            }
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    #[parallel]
+    fn test_is_public() {
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1::1",
+            "::ffff:93.184.216.34",
+            "2002:5db8:d822::1",
+            "2001:0:4136:e378:8000:63bf:a247:27dd",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::a00:1",
+            "::7f00:1",
+            "2002:7f00:1::1",
+            "2002:a9fe:a9fe::1",
+            "2001:0:4136:e378:8000:63bf:f5ff:fffe",
+            "2001:0:7f00:1:8000:63bf:a2b4:d8dd",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[actix_rt::test]
+    #[parallel]
+    async fn test_client_refuses_private_hosts() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://[::1]:8080/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://localhost/hook",
+        ] {
+            assert!(client_for(url).await.is_err(), "{}", url);
+        }
+    }
+
+    #[actix_rt::test]
+    #[parallel]
+    async fn test_deliver_signed_event() {
+        let (addr, listener) = listen();
+        let webhook = Webhook::new(
+            format!("http://{}/hook", addr),
+            "secret".to_string(),
+            Vec::new(),
+        );
+        let message = Message::new("Hello, world!".to_string());
+        let payload = payload("message.sent", &message);
+        let delivery_id = webhook.delivery_id("message.sent", &message.id());
+
+        // Local addresses are refused by client_for, so the request is sent with a plain client
+        let response = post(
+            &Client::new(),
+            &webhook,
+            "message.sent",
+            &delivery_id,
+            &payload,
+        )
+        .send()
+        .await
+        .unwrap();
+        assert!(response.status().is_success());
+        let (headers, body) = listener.join().unwrap();
+
+        assert_eq!(body, payload);
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["event"], "message.sent");
+        assert_eq!(json["data"]["id"], message.id());
+        assert_eq!(json["data"]["body"], "Hello, world!");
+
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-syntropic-event"], "message.sent");
+        assert_eq!(headers["x-syntropic-delivery"], delivery_id);
+        assert!(delivery_id.ends_with(&format!(".message.sent.{}", message.id())));
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body.as_bytes());
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(headers["x-syntropic-signature"], signature);
+    }
+}
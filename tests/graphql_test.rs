@@ -1,15 +1,25 @@
+use juniper::futures::future::ready;
 use juniper::futures::StreamExt;
 use serial_test::serial;
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
 use syntropic_api::graphql::{Mutation, Query, Subscription};
+use syntropic_api::models::audit_event::{Actor, AuditEvent, AuditEventFilter};
+use syntropic_api::models::message::Message;
+use syntropic_api::models::message_change::{ChangeKind, MessageChange};
+use syntropic_api::models::report::{Report, ReportStatus};
+use syntropic_api::models::retention::{MessageArchive, RetentionPolicy};
+use syntropic_api::models::scheduled_message::ScheduledMessage;
+use syntropic_api::moderation::Moderator;
 use syntropic_api::{data, Context};
+use time::OffsetDateTime;
 
 #[actix_rt::test]
 #[serial]
 async fn test_store_and_retrieve_message() {
-    let context = Context::from(data().await);
-    let message = Mutation::send_message(&context, "Hello, world!".to_string())
+    let context = Context::new(data().await, Actor::system());
+    let message = Mutation::send_message(&context, "Hello, world!".to_string(), None, None, None)
         .await
         .unwrap();
     assert_eq!(message.body(), "Hello, world!");
@@ -23,11 +33,36 @@ async fn test_store_and_retrieve_message() {
     assert_eq!(new_message.id(), message.id());
 }
 
+#[actix_rt::test]
+#[serial]
+async fn test_message_body_html() {
+    let context = Context::new(data().await, Actor::system());
+    let message = Mutation::send_message(
+        &context,
+        "*Hello*, <b>world</b>!".to_string(),
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        message.body_html(),
+        "<p><em>Hello</em>, &lt;b&gt;world&lt;/b&gt;!</p>\n"
+    );
+
+    let new_message = Query::message(&context, message.id())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(new_message.body_html(), message.body_html());
+}
+
 #[actix_rt::test]
 #[serial]
 async fn test_retrieve_all_messages() {
-    let context = Context::from(data().await);
-    let message = Mutation::send_message(&context, "Hello, world!".to_string())
+    let context = Context::new(data().await, Actor::system());
+    let message = Mutation::send_message(&context, "Hello, world!".to_string(), None, None, None)
         .await
         .unwrap();
     let messages = Query::messages(&context).await.unwrap();
@@ -39,10 +74,10 @@ async fn test_retrieve_all_messages() {
 #[actix_rt::test]
 #[serial]
 async fn test_subscription() {
-    let context = Context::from(data().await);
+    let context = Context::new(data().await, Actor::system());
     let mut subscription = Subscription::message_received(&context).await;
     let subscription = subscription.as_mut();
-    let message = Mutation::send_message(&context, "Hello, world!".to_string())
+    let message = Mutation::send_message(&context, "Hello, world!".to_string(), None, None, None)
         .await
         .unwrap();
     sleep(Duration::from_secs(1));
@@ -56,3 +91,672 @@ async fn test_subscription() {
         None => panic!("Subscription did not return a new message"),
     }
 }
+
+#[actix_rt::test]
+#[serial]
+async fn test_subscription_completes_on_close() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let mut subscription = Subscription::message_received(&context).await;
+    data.1.close().await;
+    assert!(subscription.as_mut().next().await.is_none());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_create_and_delete_webhook() {
+    let context = Context::new(data().await, Actor::system()).with_admin(true);
+    let webhook = Mutation::create_webhook(
+        &context,
+        "https://example.com/hook".to_string(),
+        "secret".to_string(),
+        Some(vec!["message.sent".to_string()]),
+    )
+    .await
+    .unwrap();
+    assert_eq!(webhook.url(), "https://example.com/hook");
+
+    let webhooks = Query::webhooks(&context).await.unwrap();
+    assert!(webhooks.iter().any(|w| w.id() == webhook.id()));
+
+    assert!(Mutation::delete_webhook(&context, webhook.id())
+        .await
+        .unwrap());
+    assert!(!Mutation::delete_webhook(&context, webhook.id())
+        .await
+        .unwrap());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_create_webhook_rejects_unknown_event() {
+    let context = Context::new(data().await, Actor::system()).with_admin(true);
+    let result = Mutation::create_webhook(
+        &context,
+        "https://example.com/hook".to_string(),
+        "secret".to_string(),
+        Some(vec!["message.unknown".to_string()]),
+    )
+    .await;
+    assert!(result.is_err());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_schedule_and_cancel_message() {
+    let context = Context::new(data().await, Actor::system()).with_admin(true);
+    let send_at = OffsetDateTime::now_utc() + time::Duration::hours(1);
+    let scheduled = Mutation::schedule_message(&context, "Later".to_string(), send_at)
+        .await
+        .unwrap();
+    assert_eq!(scheduled.body(), "Later");
+
+    let scheduled_messages = Query::scheduled_messages(&context).await.unwrap();
+    assert!(scheduled_messages.iter().any(|s| s.id() == scheduled.id()));
+
+    assert!(Mutation::cancel_scheduled_message(&context, scheduled.id())
+        .await
+        .unwrap());
+    assert!(
+        !Mutation::cancel_scheduled_message(&context, scheduled.id())
+            .await
+            .unwrap()
+    );
+
+    let past = OffsetDateTime::now_utc() - time::Duration::minutes(1);
+    assert!(
+        Mutation::schedule_message(&context, "Too late".to_string(), past)
+            .await
+            .is_err()
+    );
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_send_due_scheduled_messages() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system()).with_admin(true);
+    let mut subscription = Subscription::message_received(&context).await;
+    let send_at = OffsetDateTime::now_utc() - time::Duration::seconds(1);
+    let scheduled = ScheduledMessage::create(
+        &data.0,
+        ScheduledMessage::new("Right on time".to_string(), send_at),
+    )
+    .await
+    .unwrap();
+
+    let sent = ScheduledMessage::send_due(&data.0, &data.1, data.3.clone())
+        .await
+        .unwrap();
+    assert!(sent >= 1);
+    let scheduled_messages = Query::scheduled_messages(&context).await.unwrap();
+    assert!(scheduled_messages.iter().all(|s| s.id() != scheduled.id()));
+    assert!(
+        !Mutation::cancel_scheduled_message(&context, scheduled.id())
+            .await
+            .unwrap()
+    );
+
+    let message = subscription
+        .as_mut()
+        .filter(|message| ready(message.body() == "Right on time"))
+        .next()
+        .await;
+    assert!(message.is_some());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_reject_due_scheduled_message() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system()).with_admin(true);
+    let send_at = OffsetDateTime::now_utc() - time::Duration::seconds(1);
+    let scheduled = ScheduledMessage::create(
+        &data.0,
+        ScheduledMessage::new("Now forbidden".to_string(), send_at),
+    )
+    .await
+    .unwrap();
+
+    let moderator = Moderator::from_config(
+        r#"{"rules": [{"type": "words", "words": ["forbidden"], "action": "reject"}]}"#,
+    )
+    .unwrap();
+    ScheduledMessage::send_due(&data.0, &data.1, Arc::new(moderator))
+        .await
+        .unwrap();
+    let scheduled_messages = Query::scheduled_messages(&context).await.unwrap();
+    assert!(scheduled_messages.iter().all(|s| s.id() != scheduled.id()));
+
+    let filter = AuditEventFilter {
+        target_type: Some("scheduled_message".to_string()),
+        target_id: Some(scheduled.id()),
+        ..Default::default()
+    };
+    let events = AuditEvent::list(&context.pool, filter, None, 100)
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].action(), "scheduled_message.reject");
+    assert_eq!(events[0].actor(), "system");
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_expiring_message() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let mut subscription = Subscription::message_deleted(&context).await;
+    let message = Mutation::send_message(&context, "Soon gone".to_string(), None, Some(1), None)
+        .await
+        .unwrap();
+    assert!(message.expires_at().is_some());
+    assert!(Query::message(&context, message.id())
+        .await
+        .unwrap()
+        .is_some());
+
+    sleep(Duration::from_secs(2));
+    let context = Context::new(data.clone(), Actor::system());
+    assert!(Query::message(&context, message.id())
+        .await
+        .unwrap()
+        .is_none());
+    let messages = Query::messages(&context).await.unwrap();
+    assert!(messages.iter().all(|m| m.id() != message.id()));
+
+    Message::delete_expired(&data.0, &data.1, data.2.as_ref())
+        .await
+        .unwrap();
+    // Expired messages left by other tests are deleted too, so their deletions are skipped
+    let published = async {
+        while let Some(deleted) = subscription.as_mut().next().await {
+            if deleted.id() == message.id() {
+                return true;
+            }
+        }
+        false
+    };
+    assert!(actix_rt::time::timeout(Duration::from_secs(5), published)
+        .await
+        .unwrap_or(false));
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_expires_in_must_be_positive() {
+    let context = Context::new(data().await, Actor::system());
+    let result = Mutation::send_message(&context, "Never".to_string(), None, Some(0), None).await;
+    assert!(result.is_err());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_archive_expired_messages() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let mut subscription = Subscription::message_deleted(&context).await;
+    let old = Mutation::send_message(&context, "Old".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let new = Mutation::send_message(&context, "New".to_string(), None, None, None)
+        .await
+        .unwrap();
+
+    let policy = RetentionPolicy::new(None, Some(1));
+    let dir = std::env::temp_dir().join("syntropic-archive-test");
+    while MessageArchive::archive_expired(&data.0, &data.1, data.2.as_ref(), policy, dir.clone())
+        .await
+        .unwrap()
+        > 0
+    {}
+
+    let context = Context::new(data.clone(), Actor::system());
+    assert!(Query::message(&context, old.id()).await.unwrap().is_none());
+    assert!(Query::message(&context, new.id()).await.unwrap().is_some());
+
+    let status = Query::retention_status(&context).await.unwrap();
+    assert_eq!(status.expired_message_count(), 0);
+    let archive = &status.archives()[0];
+    assert_eq!(archive.last_message_id(), old.id());
+    assert!(std::path::Path::new(&archive.path()).exists());
+
+    let published = async {
+        while let Some(deleted) = subscription.as_mut().next().await {
+            if deleted.id() == old.id() {
+                return true;
+            }
+        }
+        false
+    };
+    assert!(actix_rt::time::timeout(Duration::from_secs(5), published)
+        .await
+        .unwrap_or(false));
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_send_message_is_idempotent() {
+    let context = Context::new(data().await, Actor::system());
+    let mut subscription = Subscription::message_received(&context).await;
+    let client_message_id = format!("test-{}", OffsetDateTime::now_utc().unix_timestamp_nanos());
+    let message = Mutation::send_message(
+        &context,
+        "Once".to_string(),
+        None,
+        None,
+        Some(client_message_id.clone()),
+    )
+    .await
+    .unwrap();
+    let retried = Mutation::send_message(
+        &context,
+        "Once".to_string(),
+        None,
+        None,
+        Some(client_message_id.clone()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(retried.id(), message.id());
+    assert_eq!(retried.client_message_id(), Some(client_message_id.clone()));
+
+    let messages = Query::messages(&context).await.unwrap();
+    assert_eq!(
+        messages
+            .iter()
+            .filter(|m| m.client_message_id() == Some(client_message_id.clone()))
+            .count(),
+        1
+    );
+
+    match subscription.as_mut().next().await {
+        Some(received) => {
+            assert_eq!(received.id(), message.id());
+            assert_eq!(received.client_message_id(), Some(client_message_id));
+        }
+        None => panic!("Subscription did not return a new message"),
+    }
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_retry_after_expiry_sends_again() {
+    let context = Context::new(data().await, Actor::system());
+    let client_message_id = format!("test-{}", OffsetDateTime::now_utc().unix_timestamp_nanos());
+    let message = Mutation::send_message(
+        &context,
+        "Brief".to_string(),
+        None,
+        Some(1),
+        Some(client_message_id.clone()),
+    )
+    .await
+    .unwrap();
+
+    sleep(Duration::from_secs(2));
+    let retried = Mutation::send_message(
+        &context,
+        "Brief".to_string(),
+        None,
+        None,
+        Some(client_message_id.clone()),
+    )
+    .await
+    .unwrap();
+    assert_ne!(retried.id(), message.id());
+    assert!(Query::message(&context, retried.id())
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_sync() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let initial = Query::sync(&context, None).await.unwrap();
+    assert!(initial.resync());
+
+    let message = Mutation::send_message(&context, "Synced".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let result = Query::sync(&context, Some(initial.cursor())).await.unwrap();
+    assert!(!result.resync());
+    let changes = result.changes();
+    assert_eq!(changes.len(), 1);
+    assert!(changes[0].kind() == ChangeKind::Created);
+    assert_eq!(changes[0].message_id(), message.id());
+    assert_eq!(changes[0].message().unwrap().body(), "Synced");
+
+    let result = Query::sync(&context, Some(result.cursor())).await.unwrap();
+    assert!(result.changes().is_empty());
+
+    MessageChange::compact(&data.0, Duration::ZERO)
+        .await
+        .unwrap();
+    let result = Query::sync(&context, Some(initial.cursor())).await.unwrap();
+    assert!(result.resync());
+    assert!(Query::sync(&context, Some("nonsense".to_string()))
+        .await
+        .is_err());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_moderation() {
+    let mut context = Context::new(data().await, Actor::system()).with_admin(true);
+    context.moderator = Arc::new(
+        Moderator::from_config(
+            r#"{"rules": [
+                {"type": "words", "words": ["darn"], "action": "mask"},
+                {"type": "words", "name": "spam", "words": ["casino"], "action": "reject"},
+                {"type": "links", "action": "flag"}
+            ]}"#,
+        )
+        .unwrap(),
+    );
+
+    let masked = Mutation::send_message(&context, "Darn".to_string(), None, None, None)
+        .await
+        .unwrap();
+    assert_eq!(masked.body(), "****");
+    let rejected =
+        Mutation::send_message(&context, "Win at the casino".to_string(), None, None, None).await;
+    assert!(rejected.is_err());
+
+    let first = Mutation::send_message(&context, "https://a.test".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let second = Mutation::send_message(&context, "https://b.test".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let flagged = Query::flagged_messages(&context).await.unwrap();
+    let flag = |message: &Message| {
+        flagged
+            .iter()
+            .find(|flag| flag.message_snowflake_id() == message.snowflake_id())
+            .unwrap()
+            .clone()
+    };
+    let (first_flag, second_flag) = (flag(&first), flag(&second));
+    assert_eq!(first_flag.reasons(), ["links"]);
+
+    assert!(Mutation::dismiss_flagged_message(&context, first_flag.id())
+        .await
+        .unwrap());
+    assert!(Mutation::remove_flagged_message(&context, second_flag.id())
+        .await
+        .unwrap());
+    assert!(Query::flagged_messages(&context)
+        .await
+        .unwrap()
+        .iter()
+        .all(|flag| flag.id() != first_flag.id() && flag.id() != second_flag.id()));
+
+    let context = Context::new(data().await, Actor::system());
+    assert!(Query::message(&context, first.id())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(Query::message(&context, second.id())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_reports() {
+    let context = Context::new(data().await, Actor::system()).with_admin(true);
+    let kept = Mutation::send_message(&context, "Fine".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let removed = Mutation::send_message(&context, "Rude".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let report = |message: &Message, reason: &str| {
+        Mutation::report_message(&context, message.id(), reason.to_string())
+    };
+    let kept_report = report(&kept, "Spam").await.unwrap().unwrap();
+    let first_report = report(&removed, "Rude").await.unwrap().unwrap();
+    let second_report = report(&removed, "Very rude").await.unwrap().unwrap();
+    assert!(report(&removed, " ").await.is_err());
+    assert_eq!(first_report.status(), ReportStatus::Open);
+
+    let open = Query::reports(&context, Some(ReportStatus::Open), None, None)
+        .await
+        .unwrap()
+        .nodes();
+    for report in [&kept_report, &first_report, &second_report] {
+        assert!(open.iter().any(|open| open.id() == report.id()));
+    }
+
+    let dismissed = Mutation::dismiss_report(&context, kept_report.id())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(dismissed.status(), ReportStatus::Dismissed);
+    assert!(dismissed.resolved_at().is_some());
+    assert!(Mutation::dismiss_report(&context, kept_report.id())
+        .await
+        .unwrap()
+        .is_none());
+
+    let deleted = Mutation::delete_reported_message(&context, first_report.id())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(deleted.status(), ReportStatus::Deleted);
+    let deleted = Query::reports(&context, Some(ReportStatus::Deleted), None, None)
+        .await
+        .unwrap()
+        .nodes();
+    assert!(deleted
+        .iter()
+        .any(|report| report.id() == second_report.id()));
+
+    let context = Context::new(data().await, Actor::system());
+    assert!(Query::message(&context, kept.id()).await.unwrap().is_some());
+    assert!(Query::message(&context, removed.id())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(
+        Mutation::report_message(&context, removed.id(), "Gone".to_string())
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_reports_resolved_when_message_expires() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let message = Mutation::send_message(&context, "Rude".to_string(), None, Some(1), None)
+        .await
+        .unwrap();
+    let report = Mutation::report_message(&context, message.id(), "Rude".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+
+    sleep(Duration::from_secs(2));
+    Message::delete_expired(&data.0, &data.1, data.2.as_ref())
+        .await
+        .unwrap();
+    let deleted = Report::list(&context.pool, Some(ReportStatus::Deleted), None, 100)
+        .await
+        .unwrap()
+        .nodes();
+    let resolved = deleted
+        .iter()
+        .find(|resolved| resolved.id() == report.id())
+        .unwrap();
+    assert!(resolved.resolved_at().is_some());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_audit_log() {
+    let context = Context::new(
+        data().await,
+        Actor::new("127.0.0.1".to_string(), Some("request-1".to_string())),
+    )
+    .with_admin(true);
+    let webhook = Mutation::create_webhook(
+        &context,
+        "https://example.com/audited".to_string(),
+        "secret".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    assert!(Mutation::delete_webhook(&context, webhook.id())
+        .await
+        .unwrap());
+
+    let filter = AuditEventFilter {
+        target_type: Some("webhook".to_string()),
+        target_id: Some(webhook.id()),
+        ..Default::default()
+    };
+    let events = Query::audit_events(&context, Some(filter.clone()), None, None)
+        .await
+        .unwrap();
+    let actions: Vec<String> = events.iter().map(|event| event.action()).collect();
+    assert_eq!(actions, ["webhook.delete", "webhook.create"]);
+    assert_eq!(events[0].actor(), "127.0.0.1");
+    assert_eq!(events[0].request_id().as_deref(), Some("request-1"));
+    let before = events[0].before().unwrap();
+    assert!(before.contains("https://example.com/audited"));
+    assert!(!before.contains("secret"));
+    assert!(events[0].after().is_none());
+    assert!(events[1].before().is_none());
+
+    let page = Query::audit_events(&context, Some(filter.clone()), Some(events[0].id()), None)
+        .await
+        .unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].id(), events[1].id());
+
+    let exported: Vec<String> = AuditEvent::export(context.pool.clone(), filter)
+        .map(|event| event.unwrap().action())
+        .collect()
+        .await;
+    assert_eq!(exported, ["webhook.create", "webhook.delete"]);
+
+    let send_at = OffsetDateTime::now_utc() + time::Duration::hours(1);
+    let scheduled = Mutation::schedule_message(&context, "Private plans".to_string(), send_at)
+        .await
+        .unwrap();
+    assert!(Mutation::cancel_scheduled_message(&context, scheduled.id())
+        .await
+        .unwrap());
+    let filter = AuditEventFilter {
+        target_type: Some("scheduled_message".to_string()),
+        target_id: Some(scheduled.id()),
+        ..Default::default()
+    };
+    let events = AuditEvent::list(&context.pool, filter, None, 100)
+        .await
+        .unwrap();
+    let before = events[0].before().unwrap();
+    assert!(before.contains(&scheduled.id()));
+    assert!(!before.contains("Private plans"));
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_republish() {
+    let context = Context::new(data().await, Actor::system());
+    let message = Mutation::send_message(&context, "Again".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let mut subscription = Subscription::message_received(&context).await;
+    let subscription = subscription.as_mut();
+
+    let published = Message::republish(
+        &context.pool,
+        &context.amqp_client,
+        &context.actor,
+        message.timestamp(),
+        message.timestamp() + time::Duration::milliseconds(1),
+    )
+    .await
+    .unwrap();
+    assert!(published >= 1);
+    let republished = subscription
+        .filter(|republished| ready(republished.id() == message.id()))
+        .next()
+        .await
+        .unwrap();
+    assert_eq!(republished.body(), "Again");
+
+    let filter = AuditEventFilter {
+        action: Some("message.republish".to_string()),
+        ..Default::default()
+    };
+    let events = AuditEvent::list(&context.pool, filter, None, 100)
+        .await
+        .unwrap();
+    assert!(!events.is_empty());
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_admin_required() {
+    let context = Context::new(data().await, Actor::system());
+    assert!(Query::audit_events(&context, None, None, None)
+        .await
+        .is_err());
+    assert!(Query::reports(&context, None, None, None).await.is_err());
+    assert!(Query::flagged_messages(&context).await.is_err());
+    assert!(Query::scheduled_messages(&context).await.is_err());
+    assert!(Query::webhooks(&context).await.is_err());
+    assert!(Query::incoming_webhooks(&context).await.is_err());
+    assert!(Mutation::create_webhook(
+        &context,
+        "https://example.com/hook".to_string(),
+        "secret".to_string(),
+        None,
+    )
+    .await
+    .is_err());
+
+    let message = Mutation::send_message(&context, "Rude".to_string(), None, None, None)
+        .await
+        .unwrap();
+    let report = Mutation::report_message(&context, message.id(), "Rude".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(Mutation::dismiss_report(&context, report.id())
+        .await
+        .is_err());
+    assert!(Mutation::delete_reported_message(&context, report.id())
+        .await
+        .is_err());
+    assert!(Mutation::remove_flagged_message(&context, message.id())
+        .await
+        .is_err());
+    assert!(Query::message(&context, message.id())
+        .await
+        .unwrap()
+        .is_some());
+
+    let send_at = OffsetDateTime::now_utc() + time::Duration::hours(1);
+    let scheduled = Mutation::schedule_message(&context, "Later".to_string(), send_at)
+        .await
+        .unwrap();
+    assert!(Mutation::cancel_scheduled_message(&context, scheduled.id())
+        .await
+        .is_err());
+    let context = context.with_admin(true);
+    assert!(Mutation::cancel_scheduled_message(&context, scheduled.id())
+        .await
+        .unwrap());
+}
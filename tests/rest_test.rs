@@ -0,0 +1,245 @@
+use actix_web::http::header::{
+    AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS,
+};
+use actix_web::http::StatusCode;
+use actix_web::test::{
+    call_and_read_body, call_and_read_body_json, call_service, init_service, TestRequest,
+};
+use actix_web::App;
+use serde_json::{json, Value};
+use serial_test::serial;
+use std::time::Duration;
+use syntropic_api::graphql::Mutation;
+use syntropic_api::models::attachment::Attachment;
+use syntropic_api::models::audit_event::Actor;
+use syntropic_api::{configure, data, Context};
+
+#[actix_rt::test]
+#[serial]
+async fn test_send_and_get_message() {
+    let app = init_service(App::new().app_data(data().await).configure(configure)).await;
+
+    let request = TestRequest::post()
+        .uri("/api/v1/messages")
+        .set_json(json!({ "body": "Hello, world!" }))
+        .to_request();
+    let sent: Value = call_and_read_body_json(&app, request).await;
+    assert_eq!(sent["body"], "Hello, world!");
+
+    let id = sent["id"].as_str().unwrap();
+    let request = TestRequest::get()
+        .uri(&format!("/api/v1/messages/{}", id))
+        .to_request();
+    let message: Value = call_and_read_body_json(&app, request).await;
+    assert_eq!(message, sent);
+
+    let request = TestRequest::get()
+        .uri("/api/v1/messages?limit=1")
+        .to_request();
+    let messages: Value = call_and_read_body_json(&app, request).await;
+    assert_eq!(messages[0], sent);
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_invalid_limit() {
+    let app = init_service(App::new().app_data(data().await).configure(configure)).await;
+    let request = TestRequest::get()
+        .uri("/api/v1/messages?limit=0")
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_invalid_expires_in() {
+    let app = init_service(App::new().app_data(data().await).configure(configure)).await;
+    for expires_in in [0, i64::MAX] {
+        let request = TestRequest::post()
+            .uri("/api/v1/messages")
+            .set_json(json!({ "body": "Hello, world!", "expiresIn": expires_in }))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_incoming_webhook() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    assert!(
+        Mutation::create_incoming_webhook(&context, "CI".to_string())
+            .await
+            .is_err()
+    );
+    let context = context.with_admin(true);
+    let webhook = Mutation::create_incoming_webhook(&context, "CI".to_string())
+        .await
+        .unwrap();
+    let app = init_service(App::new().app_data(data).configure(configure)).await;
+
+    let request = TestRequest::post()
+        .uri(&webhook.url())
+        .set_json(json!({ "text": "Build passed" }))
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    Mutation::delete_incoming_webhook(&context, webhook.id())
+        .await
+        .unwrap();
+    let request = TestRequest::post()
+        .uri(&webhook.url())
+        .set_json(json!({ "text": "Build passed" }))
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_send_message_with_attachment() {
+    let app = init_service(App::new().app_data(data().await).configure(configure)).await;
+
+    let request = TestRequest::post()
+        .uri("/api/v1/attachments?filename=hello.txt")
+        .insert_header((CONTENT_TYPE, "text/plain"))
+        .set_payload("Hello, world!")
+        .to_request();
+    let attachment: Value = call_and_read_body_json(&app, request).await;
+    assert_eq!(attachment["size"], 13);
+    assert_eq!(attachment["contentType"], "text/plain");
+
+    // Uploads are only served once they are sent
+    let request = TestRequest::get()
+        .uri(attachment["url"].as_str().unwrap())
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let request = TestRequest::post()
+        .uri("/api/v1/messages")
+        .set_json(json!({ "body": "See attached", "attachments": [attachment["id"]] }))
+        .to_request();
+    let message: Value = call_and_read_body_json(&app, request).await;
+    assert_eq!(message["attachments"][0]["id"], attachment["id"]);
+
+    let request = TestRequest::get()
+        .uri(attachment["url"].as_str().unwrap())
+        .to_request();
+    let contents = call_and_read_body(&app, request).await;
+    assert_eq!(contents, "Hello, world!");
+
+    let request = TestRequest::post()
+        .uri("/api/v1/messages")
+        .set_json(json!({ "body": "Reused", "attachments": [attachment["id"]] }))
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let request = TestRequest::post()
+        .uri("/api/v1/messages")
+        .set_json(json!({ "body": "Missing", "attachments": ["AAAAAAAAAAA"] }))
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_html_attachment_is_downloaded() {
+    let app = init_service(App::new().app_data(data().await).configure(configure)).await;
+
+    let request = TestRequest::post()
+        .uri("/api/v1/attachments")
+        .insert_header((CONTENT_TYPE, "text/html"))
+        .set_payload("<script>alert(document.cookie)</script>")
+        .to_request();
+    let attachment: Value = call_and_read_body_json(&app, request).await;
+    let request = TestRequest::post()
+        .uri("/api/v1/messages")
+        .set_json(json!({ "body": "See attached", "attachments": [attachment["id"]] }))
+        .to_request();
+    call_service(&app, request).await;
+
+    let request = TestRequest::get()
+        .uri(attachment["url"].as_str().unwrap())
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers.get(CONTENT_DISPOSITION).unwrap(), "attachment");
+    assert_eq!(headers.get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_unused_attachments_are_deleted() {
+    let data = data().await;
+    let app = init_service(App::new().app_data(data.clone()).configure(configure)).await;
+
+    let request = TestRequest::post()
+        .uri("/api/v1/attachments")
+        .set_payload("Never sent")
+        .to_request();
+    let attachment: Value = call_and_read_body_json(&app, request).await;
+    let key = attachment["id"].as_str().unwrap();
+    assert!(data.2.get(key).await.unwrap().is_some());
+
+    let deleted = Attachment::delete_unused(&data.0, data.2.as_ref(), Duration::ZERO)
+        .await
+        .unwrap();
+    assert!(deleted >= 1);
+    assert!(data.2.get(key).await.unwrap().is_none());
+
+    let request = TestRequest::post()
+        .uri("/api/v1/messages")
+        .set_json(json!({ "body": "Too late", "attachments": [key] }))
+        .to_request();
+    let response = call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_export_audit_events() {
+    std::env::set_var("ADMIN_TOKEN", "admin-token");
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system()).with_admin(true);
+    let webhook = Mutation::create_webhook(
+        &context,
+        "https://example.com/exported".to_string(),
+        "secret".to_string(),
+        None,
+    )
+    .await
+    .unwrap();
+    let app = init_service(App::new().app_data(data).configure(configure)).await;
+    let uri = format!("/api/v1/audit-events/export?targetId={}", webhook.id());
+
+    for token in [None, Some("Bearer wrong-token")] {
+        let mut request = TestRequest::get().uri(&uri);
+        if let Some(token) = token {
+            request = request.insert_header((AUTHORIZATION, token));
+        }
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let request = TestRequest::get()
+        .uri(&uri)
+        .insert_header((AUTHORIZATION, "Bearer admin-token"))
+        .to_request();
+    let body = call_and_read_body(&app, request).await;
+    let events: Vec<Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["action"], "webhook.create");
+    assert_eq!(events[0]["targetId"], webhook.id());
+}
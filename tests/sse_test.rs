@@ -0,0 +1,149 @@
+use actix_web::body::{to_bytes, MessageBody};
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::App;
+use juniper::futures::future::poll_fn;
+use serde_json::Value;
+use serial_test::serial;
+use std::pin::Pin;
+use std::time::Duration;
+use syntropic_api::graphql::{Mutation, Query};
+use syntropic_api::models::audit_event::Actor;
+use syntropic_api::models::message::Message;
+use syntropic_api::models::message_change::MessageChange;
+use syntropic_api::{configure, data, Context};
+
+/// The first chunk of an event stream, which holds the events replayed on connecting
+async fn replay(mut body: impl MessageBody + Unpin) -> String {
+    match poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await {
+        Some(Ok(chunk)) => String::from_utf8(chunk.to_vec()).unwrap(),
+        _ => panic!("Stream ended without replaying"),
+    }
+}
+
+/// The ID of the last event, which a client sends as `Last-Event-ID` when it reconnects
+fn last_event_id(events: &str) -> String {
+    events
+        .lines()
+        .filter_map(|line| line.strip_prefix("id: "))
+        .last()
+        .unwrap()
+        .to_string()
+}
+
+/// The IDs of the messages in the `message` events, in order
+fn message_ids(events: &str) -> Vec<String> {
+    events
+        .split("\n\n")
+        .filter(|event| event.lines().any(|line| line == "event: message"))
+        .filter_map(|event| event.lines().find_map(|line| line.strip_prefix("data: ")))
+        .map(|data| {
+            let message: Value = serde_json::from_str(data).unwrap();
+            message["id"].as_str().unwrap().to_string()
+        })
+        .collect()
+}
+
+/// The latest cursor, from which a reconnecting client has missed nothing
+async fn latest_cursor(context: &Context) -> String {
+    Query::sync(context, None).await.unwrap().cursor()
+}
+
+async fn send(context: &Context, body: &str) -> Message {
+    Mutation::send_message(context, body.to_string(), None, None, None)
+        .await
+        .unwrap()
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_replay_missed_changes() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let app = init_service(App::new().app_data(data).configure(configure)).await;
+
+    // A new client is only told where the log ends
+    let request = TestRequest::get().uri("/events").to_request();
+    let events = replay(call_service(&app, request).await.into_body()).await;
+    assert!(message_ids(&events).is_empty());
+    let disconnected_at = last_event_id(&events);
+
+    let first = send(&context, "Missed").await;
+    let second = send(&context, "Also missed").await;
+
+    let request = TestRequest::get()
+        .uri("/events")
+        .insert_header(("Last-Event-ID", disconnected_at))
+        .to_request();
+    let events = replay(call_service(&app, request).await.into_body()).await;
+    assert_eq!(message_ids(&events), [first.id(), second.id()]);
+    assert_eq!(last_event_id(&events), latest_cursor(&context).await);
+
+    // Reconnecting after the first event replays only the second
+    let after_first = events
+        .split("\n\n")
+        .find(|event| event.contains(&first.id()))
+        .map(last_event_id)
+        .unwrap();
+    let request = TestRequest::get()
+        .uri("/events")
+        .insert_header(("Last-Event-ID", after_first))
+        .to_request();
+    let events = replay(call_service(&app, request).await.into_body()).await;
+    assert_eq!(message_ids(&events), [second.id()]);
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_resync_unknown_event_id() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let too_old = latest_cursor(&context).await;
+    send(&context, "Compacted").await;
+    MessageChange::compact(&data.0, Duration::ZERO)
+        .await
+        .unwrap();
+    let app = init_service(App::new().app_data(data).configure(configure)).await;
+
+    for id in ["nonsense", too_old.as_str()] {
+        let request = TestRequest::get()
+            .uri("/events")
+            .insert_header(("Last-Event-ID", id))
+            .to_request();
+        let events = replay(call_service(&app, request).await.into_body()).await;
+        assert!(events.contains("\nevent: resync\n"), "{}", id);
+        assert!(message_ids(&events).is_empty());
+    }
+}
+
+#[actix_rt::test]
+#[serial]
+async fn test_truncated_replay() {
+    let data = data().await;
+    let context = Context::new(data.clone(), Actor::system());
+    let app = init_service(App::new().app_data(data).configure(configure)).await;
+    let disconnected_at = latest_cursor(&context).await;
+
+    // One more than the most replayed on one connection
+    let mut sent = Vec::new();
+    for i in 0..1001 {
+        sent.push(send(&context, &format!("Missed {}", i)).await.id());
+    }
+
+    // The stream ends after a truncated replay, so that the client reconnects
+    let request = TestRequest::get()
+        .uri("/events")
+        .insert_header(("Last-Event-ID", disconnected_at))
+        .to_request();
+    let body = to_bytes(call_service(&app, request).await.into_body()).await;
+    let events = String::from_utf8(body.ok().unwrap().to_vec()).unwrap();
+    assert!(events.ends_with("event: replay.truncated\ndata: {}\n\n"));
+    assert_eq!(message_ids(&events), sent[..1000]);
+
+    let request = TestRequest::get()
+        .uri("/events")
+        .insert_header(("Last-Event-ID", last_event_id(&events)))
+        .to_request();
+    let events = replay(call_service(&app, request).await.into_body()).await;
+    assert_eq!(message_ids(&events), sent[1000..]);
+    assert!(!events.contains("replay.truncated"));
+}